@@ -0,0 +1,66 @@
+//! Test-only global allocator that counts heap calls, so tests can check
+//! that audio-thread code never allocates, reallocates or frees.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+thread_local! {
+    /// Counted per thread so tests running in parallel don't see each other
+    static HEAP_CALLS: Cell<usize> = const { Cell::new(0) };
+}
+
+struct CountingAllocator;
+
+fn count() {
+    // The thread-local may already be gone while a thread shuts down
+    let _ = HEAP_CALLS.try_with(|calls| calls.set(calls.get() + 1));
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count();
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        count();
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Runs `f` and returns how many allocations, reallocations and frees it
+/// made on the current thread
+pub fn heap_calls_in(f: impl FnOnce()) -> usize {
+    let before = HEAP_CALLS.with(Cell::get);
+    f();
+    HEAP_CALLS.with(Cell::get) - before
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counts_allocations() {
+        assert!(heap_calls_in(|| drop(std::hint::black_box(vec![0u8; 16]))) >= 2);
+        assert_eq!(
+            heap_calls_in(|| {
+                std::hint::black_box([0u8; 16]);
+            }),
+            0
+        );
+    }
+}
@@ -0,0 +1,304 @@
+/// Level at which exponential segments are considered finished (-60 dB)
+const EXP_FLOOR: f32 = 0.001;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EnvelopeMode {
+    /// NoteOff is ignored; the voice runs through hold and decay
+    OneShot,
+    /// NoteOff moves the envelope into its release stage
+    Gate,
+}
+
+impl EnvelopeMode {
+    /// Convert 0-1 parameter to envelope mode
+    /// 0: OneShot, 1: Gate
+    pub fn from_param(value: i32) -> Self {
+        match value {
+            0 => EnvelopeMode::OneShot,
+            _ => EnvelopeMode::Gate,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DecayCurve {
+    Linear,
+    /// Constant-rate fall in dB, reaching -60 dB at the set time
+    Exponential,
+}
+
+impl DecayCurve {
+    /// Convert 0-1 parameter to decay curve
+    /// 0: Linear, 1: Exponential
+    pub fn from_param(value: i32) -> Self {
+        match value {
+            1 => DecayCurve::Exponential,
+            _ => DecayCurve::Linear,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EnvelopeStage {
+    Attack,
+    Hold,
+    Decay,
+    Release,
+    /// Finished; the level stays where the last stage left it
+    Done,
+}
+
+/// Converts a 0-127 time parameter to seconds.
+/// 0 is instant; 1-127 sweep exponentially from 1 ms to 10 s.
+pub fn envelope_time(value: i32) -> f32 {
+    if value <= 0 {
+        return 0.0;
+    }
+    0.001 * 10_000.0_f32.powf(value.min(127) as f32 / 127.0)
+}
+
+/// Attack, hold, decay amp envelope with a NoteOff release stage.
+pub struct Envelope {
+    stage: EnvelopeStage,
+    level: f32,
+    sample_rate: f32,
+    curve: DecayCurve,
+    attack_step: f32,
+    hold_samples: usize,
+    hold_counter: usize,
+    decay_samples: f32,
+    /// Per-sample decrement (linear) or multiplier (exponential)
+    rate: f32,
+}
+
+impl Envelope {
+    pub fn new(sample_rate: f32) -> Self {
+        Self {
+            stage: EnvelopeStage::Done,
+            level: 0.0,
+            sample_rate,
+            curve: DecayCurve::Linear,
+            attack_step: 1.0,
+            hold_samples: 0,
+            hold_counter: 0,
+            decay_samples: 0.0,
+            rate: 0.0,
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+    }
+
+    /// Starts the envelope from the attack stage. Times are 0-127
+    /// parameters, see `envelope_time`.
+    pub fn trigger(&mut self, attack: i32, hold: i32, decay: i32, curve: DecayCurve) {
+        self.curve = curve;
+        self.hold_samples = (envelope_time(hold) * self.sample_rate) as usize;
+        self.hold_counter = 0;
+        self.decay_samples = envelope_time(decay) * self.sample_rate;
+
+        let attack_samples = envelope_time(attack) * self.sample_rate;
+        if attack_samples >= 1.0 {
+            self.stage = EnvelopeStage::Attack;
+            self.level = 0.0;
+            self.attack_step = 1.0 / attack_samples;
+        } else {
+            self.stage = EnvelopeStage::Hold;
+            self.level = 1.0;
+        }
+    }
+
+    /// Moves to the release stage from the current level.
+    pub fn release(&mut self, release: i32) {
+        if self.stage == EnvelopeStage::Done {
+            return;
+        }
+        let release_samples = envelope_time(release) * self.sample_rate;
+        self.enter_fall(EnvelopeStage::Release, release_samples);
+    }
+
+    /// Advances one sample and returns the level. While `sustain` is set
+    /// the envelope stays in its hold stage.
+    pub fn process(&mut self, sustain: bool) -> f32 {
+        match self.stage {
+            EnvelopeStage::Attack => {
+                self.level += self.attack_step;
+                if self.level >= 1.0 {
+                    self.level = 1.0;
+                    self.stage = EnvelopeStage::Hold;
+                }
+            }
+            EnvelopeStage::Hold => {
+                if !sustain {
+                    if self.hold_counter < self.hold_samples {
+                        self.hold_counter += 1;
+                    } else {
+                        self.enter_fall(EnvelopeStage::Decay, self.decay_samples);
+                    }
+                }
+            }
+            EnvelopeStage::Decay | EnvelopeStage::Release => self.fall(),
+            EnvelopeStage::Done => {}
+        }
+        self.level
+    }
+
+    pub fn stage(&self) -> EnvelopeStage {
+        self.stage
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.stage == EnvelopeStage::Done
+    }
+
+    pub fn level(&self) -> f32 {
+        self.level
+    }
+
+    fn enter_fall(&mut self, stage: EnvelopeStage, samples: f32) {
+        if samples < 1.0 || self.level <= 0.0 {
+            self.stage = EnvelopeStage::Done;
+            return;
+        }
+        self.stage = stage;
+        self.rate = match self.curve {
+            DecayCurve::Linear => self.level / samples,
+            DecayCurve::Exponential => EXP_FLOOR.powf(1.0 / samples),
+        };
+    }
+
+    fn fall(&mut self) {
+        match self.curve {
+            DecayCurve::Linear => {
+                self.level = (self.level - self.rate).max(0.0);
+                if self.level <= 0.0 {
+                    self.stage = EnvelopeStage::Done;
+                }
+            }
+            DecayCurve::Exponential => {
+                self.level *= self.rate;
+                if self.level <= EXP_FLOOR {
+                    self.level = 0.0;
+                    self.stage = EnvelopeStage::Done;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(envelope: &mut Envelope, samples: usize) -> f32 {
+        let mut level = envelope.level();
+        for _ in 0..samples {
+            level = envelope.process(false);
+        }
+        level
+    }
+
+    #[test]
+    fn test_envelope_time_curve() {
+        assert_eq!(envelope_time(0), 0.0);
+        assert!((envelope_time(1) - 0.00107).abs() < 0.0001);
+        assert!((envelope_time(127) - 10.0).abs() < 0.01);
+        for value in 2..=127 {
+            assert!(envelope_time(value) > envelope_time(value - 1));
+        }
+    }
+
+    #[test]
+    fn test_mode_and_curve_from_param() {
+        assert_eq!(EnvelopeMode::from_param(0), EnvelopeMode::OneShot);
+        assert_eq!(EnvelopeMode::from_param(1), EnvelopeMode::Gate);
+        assert_eq!(DecayCurve::from_param(0), DecayCurve::Linear);
+        assert_eq!(DecayCurve::from_param(1), DecayCurve::Exponential);
+    }
+
+    #[test]
+    fn test_attack_ramps_up() {
+        let mut envelope = Envelope::new(1000.0);
+        envelope.trigger(64, 127, 0, DecayCurve::Linear);
+        assert_eq!(envelope.stage(), EnvelopeStage::Attack);
+
+        let attack_samples = (envelope_time(64) * 1000.0) as usize;
+        let halfway = run(&mut envelope, attack_samples / 2);
+        assert!((halfway - 0.5).abs() < 0.02);
+        run(&mut envelope, attack_samples);
+        assert_eq!(envelope.stage(), EnvelopeStage::Hold);
+        assert_eq!(envelope.level(), 1.0);
+    }
+
+    #[test]
+    fn test_linear_decay_is_straight() {
+        let mut envelope = Envelope::new(1000.0);
+        envelope.trigger(0, 0, 64, DecayCurve::Linear);
+        let decay_samples = (envelope_time(64) * 1000.0) as usize;
+
+        envelope.process(false);
+        let halfway = run(&mut envelope, decay_samples / 2);
+        assert!((halfway - 0.5).abs() < 0.02);
+        run(&mut envelope, decay_samples);
+        assert!(envelope.is_finished());
+        assert_eq!(envelope.level(), 0.0);
+    }
+
+    #[test]
+    fn test_exponential_decay_falls_in_db() {
+        let mut envelope = Envelope::new(1000.0);
+        envelope.trigger(0, 0, 64, DecayCurve::Exponential);
+        let decay_samples = (envelope_time(64) * 1000.0) as usize;
+
+        envelope.process(false);
+        // Halfway through in time is halfway to -60 dB
+        let halfway = run(&mut envelope, decay_samples / 2);
+        assert!((halfway - 0.0316).abs() < 0.003);
+        run(&mut envelope, decay_samples);
+        assert!(envelope.is_finished());
+    }
+
+    #[test]
+    fn test_sustain_holds_level() {
+        let mut envelope = Envelope::new(1000.0);
+        envelope.trigger(0, 0, 10, DecayCurve::Linear);
+        for _ in 0..1000 {
+            assert_eq!(envelope.process(true), 1.0);
+        }
+        run(&mut envelope, 100);
+        assert!(envelope.is_finished());
+    }
+
+    #[test]
+    fn test_release_from_current_level() {
+        let mut envelope = Envelope::new(1000.0);
+        envelope.trigger(64, 127, 127, DecayCurve::Linear);
+        let attack_samples = (envelope_time(64) * 1000.0) as usize;
+        let level = run(&mut envelope, attack_samples / 2);
+
+        envelope.release(64);
+        assert_eq!(envelope.stage(), EnvelopeStage::Release);
+        let release_samples = (envelope_time(64) * 1000.0) as usize;
+        let halfway = run(&mut envelope, release_samples / 2);
+        assert!((halfway - level * 0.5).abs() < 0.02);
+        run(&mut envelope, release_samples);
+        assert!(envelope.is_finished());
+    }
+
+    #[test]
+    fn test_instant_stages_finish_at_level() {
+        let mut envelope = Envelope::new(1000.0);
+        envelope.trigger(0, 0, 0, DecayCurve::Linear);
+        envelope.process(false);
+        // DEC 0 ends the envelope without dropping the level, leaving the
+        // fade to the voice's declick
+        assert!(envelope.is_finished());
+        assert_eq!(envelope.level(), 1.0);
+
+        envelope.trigger(0, 127, 0, DecayCurve::Linear);
+        envelope.release(0);
+        assert!(envelope.is_finished());
+        assert_eq!(envelope.level(), 1.0);
+    }
+}
@@ -0,0 +1,271 @@
+use std::f32::consts::FRAC_PI_2;
+
+/// Number of stereo aux buses channels can be routed to
+pub const AUX_BUSES: usize = 8;
+
+/// Mixer settings for one RAM channel, as 0-127 parameter values.
+#[derive(Clone, Copy)]
+pub struct ChannelStrip {
+    pub vol: i32,
+    /// 0 = hard left, 64 = center, 127 = hard right
+    pub pan: i32,
+    pub mute: i32,
+    pub solo: i32,
+    /// Main mix on (1) or off (0)
+    pub main: i32,
+    /// Aux bus 1-8, or 0 for none
+    pub bus: i32,
+}
+
+impl Default for ChannelStrip {
+    fn default() -> Self {
+        Self {
+            vol: 100,
+            pan: 64,
+            mute: 0,
+            solo: 0,
+            main: 1,
+            bus: 0,
+        }
+    }
+}
+
+/// Left/right gains for a pan setting under a constant-power (-3 dB) law.
+pub fn pan_gains(pan: i32) -> (f32, f32) {
+    let pan = pan.clamp(0, 127);
+    // Map so that 64 is exactly center: 0..=64 covers the left half, 64..=127 the right
+    let position = if pan <= 64 {
+        pan as f32 / 64.0 * 0.5
+    } else {
+        0.5 + (pan - 64) as f32 / 63.0 * 0.5
+    };
+    let angle = position * FRAC_PI_2;
+    (angle.cos(), angle.sin())
+}
+
+/// Sums the eight RAM channels with per-channel volume, pan, mute and solo.
+/// Channels can also feed an aux bus, alone or grouped with others.
+pub struct Mixer {
+    gains: [(f32, f32); 8],
+    to_main: [bool; 8],
+    buses: [Option<usize>; 8],
+}
+
+impl Mixer {
+    pub fn new() -> Self {
+        let mut mixer = Self {
+            gains: [(0.0, 0.0); 8],
+            to_main: [true; 8],
+            buses: [None; 8],
+        };
+        mixer.set_strips(&[ChannelStrip::default(); 8]);
+        mixer
+    }
+
+    /// Recomputes channel gains and routing. While any channel is soloed
+    /// only soloed channels are heard, whether muted or not.
+    pub fn set_strips(&mut self, strips: &[ChannelStrip; 8]) {
+        let any_solo = strips.iter().any(|strip| strip.solo != 0);
+        for (channel, strip) in strips.iter().enumerate() {
+            self.to_main[channel] = strip.main != 0;
+            self.buses[channel] = match strip.bus {
+                1..=8 => Some(strip.bus as usize - 1),
+                _ => None,
+            };
+        }
+        for (gains, strip) in self.gains.iter_mut().zip(strips) {
+            let audible = if any_solo {
+                strip.solo != 0
+            } else {
+                strip.mute == 0
+            };
+            *gains = if audible {
+                let level = strip.vol.clamp(0, 127) as f32 / 127.0;
+                let (left, right) = pan_gains(strip.pan);
+                (left * level, right * level)
+            } else {
+                (0.0, 0.0)
+            };
+        }
+    }
+
+    /// Each channel's post-fader share of the main mix, zero for channels
+    /// with it switched off. The main mix is their sum.
+    pub fn main_contributions(&self, frames: &[(f32, f32); 8]) -> [(f32, f32); 8] {
+        std::array::from_fn(|channel| {
+            if !self.to_main[channel] {
+                return (0.0, 0.0);
+            }
+            let (frame_l, frame_r) = frames[channel];
+            let (gain_l, gain_r) = self.gains[channel];
+            (frame_l * gain_l, frame_r * gain_r)
+        })
+    }
+
+    /// Post-fader sums for each aux bus.
+    pub fn mix_buses(&self, frames: &[(f32, f32); 8]) -> [(f32, f32); AUX_BUSES] {
+        let mut buses = [(0.0, 0.0); AUX_BUSES];
+        for (channel, (frame_l, frame_r)) in frames.iter().enumerate() {
+            if let Some(bus) = self.buses[channel] {
+                let (gain_l, gain_r) = self.gains[channel];
+                buses[bus].0 += frame_l * gain_l;
+                buses[bus].1 += frame_r * gain_r;
+            }
+        }
+        buses
+    }
+}
+
+impl Default for Mixer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strips_with(channel: usize, strip: ChannelStrip) -> [ChannelStrip; 8] {
+        let mut strips = [ChannelStrip::default(); 8];
+        strips[channel] = strip;
+        strips
+    }
+
+    /// The main mix as the plugin forms it
+    fn main_mix(mixer: &Mixer, frames: &[(f32, f32); 8]) -> (f32, f32) {
+        mixer
+            .main_contributions(frames)
+            .iter()
+            .fold((0.0, 0.0), |(left, right), (l, r)| (left + l, right + r))
+    }
+
+    #[test]
+    fn test_pan_is_constant_power() {
+        let (left, right) = pan_gains(64);
+        assert!((left - right).abs() < 1e-6);
+        assert!((left - std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-6);
+
+        assert_eq!(pan_gains(0), (1.0, 0.0));
+        let (left, right) = pan_gains(127);
+        assert!(left.abs() < 1e-6 && (right - 1.0).abs() < 1e-6);
+
+        for pan in 0..=127 {
+            let (left, right) = pan_gains(pan);
+            assert!((left * left + right * right - 1.0).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_mix_sums_all_channels() {
+        let mut mixer = Mixer::new();
+        let strip = ChannelStrip {
+            vol: 127,
+            pan: 64,
+            ..Default::default()
+        };
+        mixer.set_strips(&[strip; 8]);
+        let (left, right) = main_mix(&mixer, &[(0.1, 0.1); 8]);
+        assert!((left - 0.8 * std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-5);
+        assert!((left - right).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_volume_scales_channel() {
+        let mut mixer = Mixer::new();
+        let strip = ChannelStrip {
+            vol: 0,
+            ..Default::default()
+        };
+        mixer.set_strips(&strips_with(3, strip));
+        let mut frames = [(0.0, 0.0); 8];
+        frames[3] = (1.0, 1.0);
+        assert_eq!(main_mix(&mixer, &frames), (0.0, 0.0));
+    }
+
+    #[test]
+    fn test_mute_silences_channel() {
+        let mut mixer = Mixer::new();
+        let strip = ChannelStrip {
+            mute: 1,
+            ..Default::default()
+        };
+        mixer.set_strips(&strips_with(2, strip));
+        let mut frames = [(0.0, 0.0); 8];
+        frames[2] = (1.0, 1.0);
+        assert_eq!(main_mix(&mixer, &frames), (0.0, 0.0));
+        frames[5] = (1.0, 1.0);
+        assert!(main_mix(&mixer, &frames).0 > 0.0);
+    }
+
+    #[test]
+    fn test_solo_overrides_others() {
+        let mut mixer = Mixer::new();
+        let mut strips = [ChannelStrip::default(); 8];
+        strips[1].solo = 1;
+        strips[1].mute = 1;
+        mixer.set_strips(&strips);
+
+        let mut frames = [(1.0, 1.0); 8];
+        frames[1] = (0.0, 0.0);
+        assert_eq!(main_mix(&mixer, &frames), (0.0, 0.0));
+        frames[1] = (1.0, 1.0);
+        assert!(main_mix(&mixer, &frames).0 > 0.0);
+    }
+
+    #[test]
+    fn test_main_switch_removes_channel_from_main_only() {
+        let mut mixer = Mixer::new();
+        let strip = ChannelStrip {
+            main: 0,
+            bus: 1,
+            ..Default::default()
+        };
+        mixer.set_strips(&strips_with(4, strip));
+        let mut frames = [(0.0, 0.0); 8];
+        frames[4] = (1.0, 1.0);
+        assert_eq!(main_mix(&mixer, &frames), (0.0, 0.0));
+        assert!(mixer.mix_buses(&frames)[0].0 > 0.0);
+    }
+
+    #[test]
+    fn test_channels_group_onto_bus() {
+        let mut mixer = Mixer::new();
+        let mut strips = [ChannelStrip::default(); 8];
+        strips[0].bus = 3;
+        strips[1].bus = 3;
+        strips[2].bus = 8;
+        strips[1].mute = 1;
+        mixer.set_strips(&strips);
+
+        let buses = mixer.mix_buses(&[(1.0, 1.0); 8]);
+        let single = mixer.gains[0];
+        // Muted channels drop out of their bus as well
+        assert!((buses[2].0 - single.0).abs() < 1e-6);
+        assert!((buses[7].1 - single.1).abs() < 1e-6);
+        for bus in [0, 1, 3, 4, 5, 6] {
+            assert_eq!(buses[bus], (0.0, 0.0));
+        }
+        // Routing to a bus doesn't take a channel off the main mix
+        assert!((main_mix(&mixer, &[(1.0, 1.0); 8]).0 - 7.0 * single.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_contributions_apply_channel_gains() {
+        let mut mixer = Mixer::new();
+        let mut strips = [ChannelStrip::default(); 8];
+        strips[3].main = 0;
+        strips[5].pan = 0;
+        mixer.set_strips(&strips);
+
+        let frames = std::array::from_fn(|channel| (0.1 * channel as f32, -0.05));
+        let contributions = mixer.main_contributions(&frames);
+        assert_eq!(contributions[3], (0.0, 0.0));
+        // Hard left
+        assert!(contributions[5].0 > 0.0);
+        assert!(contributions[5].1.abs() < 1e-6);
+        let (gain_l, gain_r) = mixer.gains[2];
+        assert!((contributions[2].0 - 0.2 * gain_l).abs() < 1e-6);
+        assert!((contributions[2].1 + 0.05 * gain_r).abs() < 1e-6);
+    }
+}
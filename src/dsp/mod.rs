@@ -1,3 +1,7 @@
 pub mod effects;
+pub mod envelope;
 pub mod filter;
+pub mod mixer;
+pub mod resampler;
+pub mod sample_buffer;
 pub mod sample_engine;
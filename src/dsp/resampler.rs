@@ -0,0 +1,346 @@
+use audioadapter_buffers::direct::InterleavedSlice;
+use rubato::{
+    Async, FixedAsync, Resampler, SincInterpolationParameters, SincInterpolationType,
+    WindowFunction,
+};
+
+/// Internal clock of the emulated RAM machines, matching the hardware
+pub const ENGINE_SAMPLE_RATE: f32 = 44100.0;
+
+/// Frames per resampler chunk on the fixed side
+const CHUNK_SIZE: usize = 64;
+
+/// Builds a sinc resampler from `from_rate` to `to_rate`.
+/// Returns None when the rates match so callers can pass frames through.
+fn make_resampler(
+    from_rate: f32,
+    to_rate: f32,
+    channels: usize,
+    fixed: FixedAsync,
+) -> Option<Async<f32>> {
+    if from_rate == to_rate {
+        return None;
+    }
+    let params = SincInterpolationParameters {
+        sinc_len: 64,
+        f_cutoff: 0.95,
+        interpolation: SincInterpolationType::Linear,
+        oversampling_factor: 128,
+        window: WindowFunction::BlackmanHarris2,
+    };
+    Async::<f32>::new_sinc(
+        to_rate as f64 / from_rate as f64,
+        1.0,
+        &params,
+        CHUNK_SIZE,
+        channels,
+        fixed,
+    )
+    .ok()
+}
+
+/// Runs one chunk of interleaved frames through the resampler.
+/// Returns the number of frames written to `output`.
+fn process_chunk(
+    resampler: &mut Async<f32>,
+    channels: usize,
+    input: &[f32],
+    output: &mut [f32],
+) -> usize {
+    let input_frames = input.len() / channels;
+    let output_frames = output.len() / channels;
+    let (Ok(input), Ok(mut output)) = (
+        InterleavedSlice::new(input, channels, input_frames),
+        InterleavedSlice::new_mut(output, channels, output_frames),
+    ) else {
+        return 0;
+    };
+    resampler
+        .process_into_buffer(&input, &mut output, None)
+        .map(|(_, produced)| produced)
+        .unwrap_or(0)
+}
+
+/// Converts a source running at the engine clock to the host rate.
+/// Source frames are pulled in chunks as the host asks for output.
+pub struct OutputResampler<const N: usize> {
+    resampler: Option<Async<f32>>,
+    input: Vec<f32>,
+    output: Vec<f32>,
+    output_frames: usize,
+    output_read: usize,
+}
+
+impl<const N: usize> OutputResampler<N> {
+    pub fn new(source_rate: f32, host_rate: f32) -> Self {
+        let resampler = make_resampler(source_rate, host_rate, N, FixedAsync::Output);
+        let (input_len, output_len) = resampler
+            .as_ref()
+            .map(|r| (r.input_frames_max() * N, r.output_frames_max() * N))
+            .unwrap_or((0, 0));
+        Self {
+            resampler,
+            input: vec![0.0; input_len],
+            output: vec![0.0; output_len],
+            output_frames: 0,
+            output_read: 0,
+        }
+    }
+
+    /// Returns the next host-rate frame, calling `render` for each source
+    /// frame the resampler needs.
+    pub fn next_frame(&mut self, mut render: impl FnMut() -> [f32; N]) -> [f32; N] {
+        let Some(resampler) = self.resampler.as_mut() else {
+            return render();
+        };
+
+        if self.output_read >= self.output_frames {
+            let needed = resampler.input_frames_next();
+            let wanted = resampler.output_frames_next();
+            for frame in self.input.chunks_exact_mut(N).take(needed) {
+                frame.copy_from_slice(&render());
+            }
+            self.output_frames = process_chunk(
+                resampler,
+                N,
+                &self.input[..needed * N],
+                &mut self.output[..wanted * N],
+            );
+            self.output_read = 0;
+            if self.output_frames == 0 {
+                return [0.0; N];
+            }
+        }
+
+        let start = self.output_read * N;
+        let mut frame = [0.0; N];
+        frame.copy_from_slice(&self.output[start..start + N]);
+        self.output_read += 1;
+        frame
+    }
+
+    /// Delay the resampler adds, in host frames
+    pub fn latency(&self) -> usize {
+        self.resampler
+            .as_ref()
+            .map_or(0, |resampler| resampler.output_delay())
+    }
+
+    pub fn reset(&mut self) {
+        if let Some(resampler) = self.resampler.as_mut() {
+            resampler.reset();
+        }
+        self.output_frames = 0;
+        self.output_read = 0;
+    }
+}
+
+/// Converts host-rate input to the engine clock.
+/// Host frames are collected into chunks and handed on once converted.
+pub struct InputResampler<const N: usize> {
+    resampler: Option<Async<f32>>,
+    input: Vec<f32>,
+    input_frames: usize,
+    output: Vec<f32>,
+    /// Host frames per engine frame
+    host_per_engine: f64,
+}
+
+impl<const N: usize> InputResampler<N> {
+    pub fn new(host_rate: f32, engine_rate: f32) -> Self {
+        let resampler = make_resampler(host_rate, engine_rate, N, FixedAsync::Input);
+        let (input_len, output_len) = resampler
+            .as_ref()
+            .map(|r| (r.input_frames_max() * N, r.output_frames_max() * N))
+            .unwrap_or((0, 0));
+        Self {
+            resampler,
+            input: vec![0.0; input_len],
+            input_frames: 0,
+            output: vec![0.0; output_len],
+            host_per_engine: host_rate as f64 / engine_rate as f64,
+        }
+    }
+
+    /// Queues one host-rate frame and calls `sink` for every engine-rate
+    /// frame that becomes available.
+    pub fn push_frame(&mut self, frame: [f32; N], mut sink: impl FnMut([f32; N])) {
+        let Some(resampler) = self.resampler.as_mut() else {
+            sink(frame);
+            return;
+        };
+
+        let start = self.input_frames * N;
+        self.input[start..start + N].copy_from_slice(&frame);
+        self.input_frames += 1;
+
+        let needed = resampler.input_frames_next();
+        if self.input_frames < needed {
+            return;
+        }
+
+        let wanted = resampler.output_frames_next();
+        let produced = process_chunk(
+            resampler,
+            N,
+            &self.input[..needed * N],
+            &mut self.output[..wanted * N],
+        );
+        self.input_frames = 0;
+
+        for converted in self.output[..produced * N].chunks_exact(N) {
+            let mut out = [0.0; N];
+            out.copy_from_slice(converted);
+            sink(out);
+        }
+    }
+
+    /// Delay from a host frame going in to it coming out, in host frames:
+    /// waiting for a full chunk plus the resampler's own delay
+    pub fn latency(&self) -> usize {
+        self.resampler.as_ref().map_or(0, |resampler| {
+            CHUNK_SIZE + (resampler.output_delay() as f64 * self.host_per_engine).round() as usize
+        })
+    }
+
+    pub fn reset(&mut self) {
+        if let Some(resampler) = self.resampler.as_mut() {
+            resampler.reset();
+        }
+        self.input_frames = 0;
+    }
+}
+
+/// Fixed-size FIFO between an `InputResampler` and code that consumes one
+/// engine frame at a time. Absorbs the resamplers' differing chunk timing.
+pub struct FrameQueue<const N: usize> {
+    frames: Vec<[f32; N]>,
+    head: usize,
+    len: usize,
+    last: [f32; N],
+}
+
+impl<const N: usize> FrameQueue<N> {
+    pub fn new() -> Self {
+        Self {
+            frames: vec![[0.0; N]; CHUNK_SIZE * 4],
+            head: 0,
+            len: 0,
+            last: [0.0; N],
+        }
+    }
+
+    /// Queues a frame, dropping the oldest one when full
+    pub fn push(&mut self, frame: [f32; N]) {
+        let capacity = self.frames.len();
+        if self.len == capacity {
+            self.head = (self.head + 1) % capacity;
+            self.len -= 1;
+        }
+        self.frames[(self.head + self.len) % capacity] = frame;
+        self.len += 1;
+    }
+
+    /// Takes the oldest frame, repeating the last one when empty
+    pub fn pop(&mut self) -> [f32; N] {
+        if self.len > 0 {
+            self.last = self.frames[self.head];
+            self.head = (self.head + 1) % self.frames.len();
+            self.len -= 1;
+        }
+        self.last
+    }
+
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+        self.last = [0.0; N];
+    }
+}
+
+impl<const N: usize> Default for FrameQueue<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matching_rates_pass_through() {
+        let mut output = OutputResampler::<2>::new(44100.0, 44100.0);
+        assert_eq!(output.next_frame(|| [0.25, -0.25]), [0.25, -0.25]);
+
+        let mut input = InputResampler::<2>::new(44100.0, 44100.0);
+        let mut received = Vec::new();
+        input.push_frame([0.5, 0.5], |frame| received.push(frame));
+        assert_eq!(received, vec![[0.5, 0.5]]);
+    }
+
+    #[test]
+    fn test_output_pulls_source_at_engine_rate() {
+        let mut output = OutputResampler::<2>::new(ENGINE_SAMPLE_RATE, 96000.0);
+        let mut rendered = 0;
+        for _ in 0..96000 {
+            output.next_frame(|| {
+                rendered += 1;
+                [0.5, 0.5]
+            });
+        }
+        // One second at the host rate consumes about one second of engine frames
+        assert!((rendered as f32 - ENGINE_SAMPLE_RATE).abs() < 2.0 * CHUNK_SIZE as f32);
+    }
+
+    #[test]
+    fn test_output_settles_to_source_level() {
+        let mut output = OutputResampler::<2>::new(ENGINE_SAMPLE_RATE, 48000.0);
+        let mut last = [0.0; 2];
+        for _ in 0..4800 {
+            last = output.next_frame(|| [0.5, -0.5]);
+        }
+        assert!((last[0] - 0.5).abs() < 0.01);
+        assert!((last[1] + 0.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_input_delivers_engine_rate_frames() {
+        let mut input = InputResampler::<4>::new(48000.0, ENGINE_SAMPLE_RATE);
+        let mut received = 0;
+        for _ in 0..48000 {
+            input.push_frame([0.1; 4], |_| received += 1);
+        }
+        assert!((received as f32 - ENGINE_SAMPLE_RATE).abs() < 2.0 * CHUNK_SIZE as f32);
+    }
+
+    #[test]
+    fn test_latency() {
+        assert_eq!(OutputResampler::<2>::new(44100.0, 44100.0).latency(), 0);
+        assert_eq!(InputResampler::<2>::new(44100.0, 44100.0).latency(), 0);
+
+        // Sinc resamplers delay by about half their filter length
+        let output = OutputResampler::<2>::new(ENGINE_SAMPLE_RATE, 48000.0);
+        assert!((16..=64).contains(&output.latency()));
+        let input = InputResampler::<2>::new(48000.0, ENGINE_SAMPLE_RATE);
+        assert!(input.latency() > CHUNK_SIZE);
+        assert!(input.latency() <= CHUNK_SIZE + 64);
+    }
+
+    #[test]
+    fn test_frame_queue_order_and_underrun() {
+        let mut queue = FrameQueue::<1>::new();
+        queue.push([1.0]);
+        queue.push([2.0]);
+        assert_eq!(queue.pop(), [1.0]);
+        assert_eq!(queue.pop(), [2.0]);
+        // Empty: hold the last frame
+        assert_eq!(queue.pop(), [2.0]);
+
+        for i in 0..CHUNK_SIZE * 4 + 3 {
+            queue.push([i as f32]);
+        }
+        // Full: the oldest frames were dropped
+        assert_eq!(queue.pop(), [3.0]);
+    }
+}
@@ -0,0 +1,369 @@
+use std::any::Any;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicI16, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::dsp::sample_engine::BufferFormat;
+
+/// Length and format of a buffer. Kept apart from the struct so both can
+/// live in memory shared between processes, next to the samples.
+#[repr(C)]
+#[derive(Default)]
+pub struct BufferHeader {
+    /// Samples written so far in the low bits, everything below it
+    /// readable, and the format in the top byte. One atomic, so readers
+    /// never pair a length with the wrong format.
+    state: AtomicUsize,
+}
+
+impl BufferHeader {
+    const FORMAT_SHIFT: u32 = usize::BITS - 8;
+    const LEN_MASK: usize = (1 << Self::FORMAT_SHIFT) - 1;
+
+    fn load(&self, order: Ordering) -> (usize, BufferFormat) {
+        let state = self.state.load(order);
+        let format = BufferFormat::from_param((state >> Self::FORMAT_SHIFT) as i32);
+        (state & Self::LEN_MASK, format)
+    }
+
+    fn store(&self, len: usize, format: BufferFormat, order: Ordering) {
+        let state = (format as usize) << Self::FORMAT_SHIFT | len.min(Self::LEN_MASK);
+        self.state.store(state, order);
+    }
+}
+
+/// Fixed-capacity sample storage written by one recorder while any number
+/// of voices read it. Shared through an `Arc`, so handing a recording to
+/// the players never copies or allocates.
+///
+/// A buffer uses a region of its storage, all of it unless moved with
+/// `carve`. Buffers made by `pool` share one storage and carve their
+/// recordings out of it.
+pub struct SampleBuffer {
+    header: NonNull<BufferHeader>,
+    storage: NonNull<[AtomicI16]>,
+    /// Start and length of the region in use, packed so readers never see
+    /// half a move
+    region: AtomicU64,
+    /// Keeps memory allocated elsewhere alive; None when the buffer
+    /// allocated the memory itself and frees it on drop
+    owner: Option<Arc<dyn Any + Send + Sync>>,
+}
+
+// Safety: everything behind the pointers is atomic
+unsafe impl Send for SampleBuffer {}
+unsafe impl Sync for SampleBuffer {}
+
+/// Storage and headers of the buffers made by `SampleBuffer::pool`
+struct Pool {
+    headers: Box<[BufferHeader]>,
+    samples: Box<[AtomicI16]>,
+}
+
+/// Silence, allocated zeroed so untouched pages cost no memory
+fn zeroed(capacity: usize) -> Box<[AtomicI16]> {
+    let samples = vec![0i16; capacity].into_boxed_slice();
+    // Safety: AtomicI16 has the same in-memory representation as i16
+    unsafe { Box::from_raw(Box::into_raw(samples) as *mut [AtomicI16]) }
+}
+
+fn pack_region(start: usize, capacity: usize) -> u64 {
+    let limit = u32::MAX as usize;
+    (start.min(limit) as u64) << 32 | capacity.min(limit) as u64
+}
+
+fn unpack_region(region: u64) -> (usize, usize) {
+    ((region >> 32) as usize, (region & u32::MAX as u64) as usize)
+}
+
+impl SampleBuffer {
+    /// Allocates room for `capacity` samples up front
+    pub fn new(capacity: usize) -> Self {
+        Self::allocate(zeroed(capacity), 0, BufferFormat::Mono12)
+    }
+
+    /// A full buffer holding `samples`, interleaved for stereo formats
+    pub fn from_samples(samples: Vec<i16>, format: BufferFormat) -> Self {
+        let len = samples.len();
+        Self::allocate(
+            samples.into_iter().map(AtomicI16::new).collect(),
+            len,
+            format,
+        )
+    }
+
+    fn allocate(samples: Box<[AtomicI16]>, len: usize, format: BufferFormat) -> Self {
+        let header = Box::new(BufferHeader::default());
+        header.store(len, format, Ordering::Relaxed);
+        Self {
+            region: AtomicU64::new(pack_region(0, samples.len())),
+            header: NonNull::from(Box::leak(header)),
+            storage: NonNull::from(Box::leak(samples)),
+            owner: None,
+        }
+    }
+
+    /// `N` empty buffers sharing room for `capacity` samples. Each has no
+    /// room of its own until given a region with `carve`.
+    pub fn pool<const N: usize>(capacity: usize) -> [Arc<Self>; N] {
+        let pool = Arc::new(Pool {
+            headers: (0..N).map(|_| BufferHeader::default()).collect(),
+            samples: zeroed(capacity),
+        });
+        std::array::from_fn(|index| {
+            // Safety: the pool owns both and only hands out atomics
+            let buffer = unsafe {
+                Self::from_raw_parts(
+                    NonNull::from(&pool.headers[index]),
+                    NonNull::from(&pool.samples[..]),
+                    pool.clone(),
+                )
+            };
+            buffer.region.store(0, Ordering::Release);
+            Arc::new(buffer)
+        })
+    }
+
+    /// A buffer over memory something else owns, such as a mapped file.
+    /// Zeroed memory reads as an empty 12-bit mono buffer.
+    ///
+    /// # Safety
+    ///
+    /// `header` and `samples` must stay valid while `owner` is alive and
+    /// must only be accessed atomically.
+    pub unsafe fn from_raw_parts(
+        header: NonNull<BufferHeader>,
+        samples: NonNull<[AtomicI16]>,
+        owner: Arc<dyn Any + Send + Sync>,
+    ) -> Self {
+        Self {
+            region: AtomicU64::new(pack_region(0, samples.len())),
+            header,
+            storage: samples,
+            owner: Some(owner),
+        }
+    }
+
+    fn header(&self) -> &BufferHeader {
+        // Safety: valid until drop, see `allocate` and `from_raw_parts`
+        unsafe { self.header.as_ref() }
+    }
+
+    fn storage(&self) -> &[AtomicI16] {
+        // Safety: valid until drop, see `allocate` and `from_raw_parts`
+        unsafe { self.storage.as_ref() }
+    }
+
+    fn samples(&self) -> &[AtomicI16] {
+        let (start, capacity) = unpack_region(self.region.load(Ordering::Acquire));
+        // `carve` keeps the region inside the storage
+        &self.storage()[start..start + capacity]
+    }
+
+    /// Room in samples
+    pub fn capacity(&self) -> usize {
+        unpack_region(self.region.load(Ordering::Acquire)).1
+    }
+
+    /// Moves the buffer onto `capacity` samples of its storage from
+    /// `start`, clamped to the storage. What was written is kept, up to
+    /// the new capacity, if the start stays put; otherwise the buffer
+    /// empties. Buffers sharing storage must be kept from overlapping.
+    pub fn carve(&self, start: usize, capacity: usize) {
+        let storage = self.storage().len();
+        let start = start.min(storage);
+        let capacity = capacity.min(storage - start);
+        let (old_start, _) = unpack_region(self.region.load(Ordering::Relaxed));
+        let len = if start == old_start {
+            self.len().min(capacity)
+        } else {
+            0
+        };
+        // Length before region, for readers that check the length first
+        self.header().store(len, self.format(), Ordering::Release);
+        self.region
+            .store(pack_region(start, capacity), Ordering::Release);
+    }
+
+    /// Samples written, counting both sides of a stereo frame. Never more
+    /// than the capacity, even if another process wrote a bad length into
+    /// shared memory.
+    pub fn len(&self) -> usize {
+        self.header().load(Ordering::Acquire).0.min(self.capacity())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Length in frames
+    pub fn frames(&self) -> usize {
+        let (len, format) = self.header().load(Ordering::Acquire);
+        len.min(self.capacity()) / format.channels()
+    }
+
+    pub fn format(&self) -> BufferFormat {
+        self.header().load(Ordering::Acquire).1
+    }
+
+    /// Changes the layout, emptying the buffer
+    pub fn set_format(&self, format: BufferFormat) {
+        self.header().store(0, format, Ordering::Release);
+    }
+
+    /// Sample at `index`, or silence past the written length
+    pub fn get(&self, index: usize) -> i16 {
+        if index >= self.len() {
+            return 0;
+        }
+        // The region may have shrunk since the length was read
+        self.samples()
+            .get(index)
+            .map_or(0, |sample| sample.load(Ordering::Relaxed))
+    }
+
+    /// Overwrites a sample that has already been written
+    pub fn set(&self, index: usize, value: i16) {
+        if index < self.len() {
+            if let Some(sample) = self.samples().get(index) {
+                sample.store(value, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Appends a sample. Returns false when the buffer is full.
+    pub fn push(&self, value: i16) -> bool {
+        let (len, format) = self.header().load(Ordering::Relaxed);
+        let Some(slot) = self.samples().get(len) else {
+            return false;
+        };
+        slot.store(value, Ordering::Relaxed);
+        self.header().store(len + 1, format, Ordering::Release);
+        true
+    }
+
+    pub fn clear(&self) {
+        self.header().store(0, self.format(), Ordering::Release);
+    }
+
+    /// Replaces the contents with a copy of `other`, as much as fits
+    pub fn copy_from(&self, other: &SampleBuffer) {
+        self.set_format(other.format());
+        for index in 0..other.len() {
+            if !self.push(other.get(index)) {
+                break;
+            }
+        }
+    }
+
+    /// Copies the written samples out
+    pub fn to_vec(&self) -> Vec<i16> {
+        let samples = self.samples();
+        samples[..self.len().min(samples.len())]
+            .iter()
+            .map(|sample| sample.load(Ordering::Relaxed))
+            .collect()
+    }
+}
+
+impl Drop for SampleBuffer {
+    fn drop(&mut self) {
+        if self.owner.is_none() {
+            // Safety: leaked from boxes in `allocate` and freed only here
+            unsafe {
+                drop(Box::from_raw(self.header.as_ptr()));
+                drop(Box::from_raw(self.storage.as_ptr()));
+            }
+        }
+    }
+}
+
+impl Default for SampleBuffer {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push_stops_at_capacity() {
+        let buffer = SampleBuffer::new(3);
+        assert!(buffer.push(1));
+        assert!(buffer.push(2));
+        assert!(buffer.push(3));
+        assert!(!buffer.push(4));
+        assert_eq!(buffer.to_vec(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_reads_past_length_are_silent() {
+        let buffer = SampleBuffer::new(4);
+        buffer.push(100);
+        assert_eq!(buffer.get(0), 100);
+        assert_eq!(buffer.get(1), 0);
+        assert_eq!(buffer.get(10), 0);
+        buffer.clear();
+        assert_eq!(buffer.get(0), 0);
+    }
+
+    #[test]
+    fn test_format_sets_frame_count() {
+        let buffer = SampleBuffer::from_samples(vec![1, 2, 3, 4], BufferFormat::Stereo16);
+        assert_eq!(buffer.frames(), 2);
+        buffer.set_format(BufferFormat::Mono12);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_copy_stops_at_capacity() {
+        let source = SampleBuffer::from_samples(vec![1, 2, 3, 4], BufferFormat::Stereo16);
+        let buffer = SampleBuffer::new(2);
+        buffer.copy_from(&source);
+        assert_eq!(buffer.format(), BufferFormat::Stereo16);
+        assert_eq!(buffer.to_vec(), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_pooled_buffers_write_to_their_own_regions() {
+        let [first, second] = SampleBuffer::pool::<2>(6);
+        assert_eq!(first.capacity(), 0);
+        assert!(!first.push(1));
+
+        first.carve(0, 2);
+        second.carve(2, 4);
+        for value in 1..=3 {
+            first.push(value);
+            second.push(value * 10);
+        }
+        assert_eq!(first.to_vec(), vec![1, 2]);
+        assert_eq!(second.to_vec(), vec![10, 20, 30]);
+
+        // Shrinking in place keeps what fits, moving empties
+        second.carve(2, 2);
+        assert_eq!(second.to_vec(), vec![10, 20]);
+        second.carve(4, 2);
+        assert!(second.is_empty());
+        // Regions are clamped to the storage
+        second.carve(5, 10);
+        assert_eq!(second.capacity(), 1);
+    }
+
+    #[test]
+    fn test_external_memory_is_left_to_its_owner() {
+        let owner = Arc::new((BufferHeader::default(), [0i16; 4].map(AtomicI16::new)));
+        let buffer = unsafe {
+            SampleBuffer::from_raw_parts(
+                NonNull::from(&owner.0),
+                NonNull::from(&owner.1[..]),
+                owner.clone(),
+            )
+        };
+        assert!(buffer.push(7));
+        drop(buffer);
+        assert_eq!(owner.1[0].load(Ordering::Relaxed), 7);
+        assert_eq!(owner.0.load(Ordering::Relaxed).0, 1);
+    }
+}
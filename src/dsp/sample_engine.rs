@@ -1,19 +1,196 @@
+use std::sync::Arc;
+
+use crate::dsp::sample_buffer::SampleBuffer;
+
+/// Half-width of the windowed-sinc kernel in samples (16 taps total)
+const SINC_HALF_TAPS: isize = 8;
+
+/// Maximum number of slices per buffer (Octatrack LEN range)
+pub const MAX_SLICES: usize = 128;
+
+/// Effective bit depth left at SRR 127
+const SRR_MIN_BITS: f32 = 2.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InterpolationMode {
+    /// Nearest-lower sample, no interpolation (raw DSP aliasing)
+    DropSample,
+    /// Two-point linear (Octatrack-matched default)
+    Linear,
+    /// Four-point cubic Hermite
+    Cubic,
+    /// Blackman-windowed sinc, band-limited when pitched up
+    Sinc,
+}
+
+impl InterpolationMode {
+    /// Convert 0-3 parameter to interpolation mode
+    /// 0: DropSample, 1: Linear, 2: Cubic, 3: Sinc
+    pub fn from_param(value: i32) -> Self {
+        match value {
+            0 => InterpolationMode::DropSample,
+            1 => InterpolationMode::Linear,
+            2 => InterpolationMode::Cubic,
+            3 => InterpolationMode::Sinc,
+            _ => InterpolationMode::Linear,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoopMode {
+    /// Play once and stop at END
+    Off,
+    /// Jump back to the loop start on reaching END
+    Loop,
+    /// Bounce between loop start and END (PIPO)
+    PingPong,
+}
+
+impl LoopMode {
+    /// Convert 0-2 parameter to loop mode
+    /// 0: Off, 1: Loop, 2: PingPong
+    pub fn from_param(value: i32) -> Self {
+        match value {
+            0 => LoopMode::Off,
+            1 => LoopMode::Loop,
+            2 => LoopMode::PingPong,
+            _ => LoopMode::Off,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PlaybackDirection {
+    Forward,
+    Reverse,
+}
+
+impl PlaybackDirection {
+    /// Convert 0-1 parameter to playback direction
+    /// 0: Forward, 1: Reverse
+    pub fn from_param(value: i32) -> Self {
+        match value {
+            1 => PlaybackDirection::Reverse,
+            _ => PlaybackDirection::Forward,
+        }
+    }
+
+    fn sign(self) -> f64 {
+        match self {
+            PlaybackDirection::Forward => 1.0,
+            PlaybackDirection::Reverse => -1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DitherMode {
+    /// Plain rounding to the reduced bit depth
+    Off,
+    /// Triangular (TPDF) dither of +/-1 LSB before rounding
+    Tpdf,
+}
+
+impl DitherMode {
+    /// Convert 0-1 parameter to dither mode
+    /// 0: Off, 1: Tpdf
+    pub fn from_param(value: i32) -> Self {
+        match value {
+            1 => DitherMode::Tpdf,
+            _ => DitherMode::Off,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BufferFormat {
+    /// Machinedrum RAM: 12-bit mono, stored as -2048..=2047 in each i16
+    Mono12,
+    /// Octatrack Flex: 16-bit stereo, interleaved L/R full-range i16
+    Stereo16,
+}
+
+impl BufferFormat {
+    /// Convert 0-1 parameter to buffer format
+    /// 0: Mono12, 1: Stereo16
+    pub fn from_param(value: i32) -> Self {
+        match value {
+            1 => BufferFormat::Stereo16,
+            _ => BufferFormat::Mono12,
+        }
+    }
+
+    /// Interleaved samples per frame
+    pub fn channels(self) -> usize {
+        match self {
+            BufferFormat::Mono12 => 1,
+            BufferFormat::Stereo16 => 2,
+        }
+    }
+
+    /// Converts a normalized sample to this format's stored integer value.
+    pub fn quantize(self, sample: f32) -> i16 {
+        let full_scale = self.full_scale();
+        (sample * full_scale)
+            .round()
+            .clamp(-full_scale, full_scale - 1.0) as i16
+    }
+
+    /// Converts a stored integer value back to a normalized sample.
+    #[inline]
+    pub fn normalize(self, value: i16) -> f32 {
+        value as f32 / self.full_scale()
+    }
+
+    /// Stored resolution in bits
+    pub fn bits(self) -> f32 {
+        match self {
+            BufferFormat::Mono12 => 12.0,
+            BufferFormat::Stereo16 => 16.0,
+        }
+    }
+
+    fn full_scale(self) -> f32 {
+        match self {
+            BufferFormat::Mono12 => 2048.0,
+            BufferFormat::Stereo16 => 32768.0,
+        }
+    }
+}
+
 pub struct SampleEngine {
-    buffer: Vec<i16>,
+    /// Shared so several voices can play one recording
+    buffer: Arc<SampleBuffer>,
+    /// Frame offsets of each slice start, followed by the buffer end
+    slice_points: Vec<usize>,
     position: f64,
+    /// +1.0 while moving forward, -1.0 while moving backward
+    direction: f64,
     sample_rate: f32,
     srr_counter: f32,
     srr_hold_sample: f32,
+    srr_hold_sample_r: f32,
+    /// xorshift32 state for TPDF dither
+    dither_seed: u32,
 }
 
 impl SampleEngine {
     pub fn new(sample_rate: f32) -> Self {
         Self {
-            buffer: Vec::new(),
+            buffer: Arc::new(SampleBuffer::default()),
+            slice_points: {
+                let mut points = Vec::with_capacity(MAX_SLICES + 1);
+                points.extend([0, 0]);
+                points
+            },
             position: 0.0,
+            direction: 1.0,
             sample_rate,
             srr_counter: 0.0,
             srr_hold_sample: 0.0,
+            srr_hold_sample_r: 0.0,
+            dither_seed: 0x1234_5678,
         }
     }
 
@@ -22,73 +199,360 @@ impl SampleEngine {
     }
 
     pub fn load_buffer(&mut self, samples: Vec<i16>) {
-        self.buffer = samples;
+        self.load_buffer_with_format(samples, BufferFormat::Mono12);
+    }
+
+    pub fn load_buffer_with_format(&mut self, samples: Vec<i16>, format: BufferFormat) {
+        self.set_shared_buffer(Arc::new(SampleBuffer::from_samples(samples, format)));
         self.position = 0.0;
     }
 
+    /// Plays from a buffer shared with other engines and recorders. The play
+    /// position is left alone; the next trigger sets it. Returns the buffer
+    /// it replaces, so the caller decides which thread frees it.
+    pub fn set_shared_buffer(&mut self, buffer: Arc<SampleBuffer>) -> Arc<SampleBuffer> {
+        let previous = std::mem::replace(&mut self.buffer, buffer);
+        self.update_slice_points(self.slice_count());
+        previous
+    }
+
+    /// Whether this engine already plays from `buffer`.
+    pub fn shares_buffer(&self, buffer: &Arc<SampleBuffer>) -> bool {
+        Arc::ptr_eq(&self.buffer, buffer)
+    }
+
+    /// Re-splits slices after the shared buffer has changed length
+    pub fn refresh_slices(&mut self) {
+        if self.slice_points.last() != Some(&self.buffer_len()) {
+            self.update_slice_points(self.slice_count());
+        }
+    }
+
+    /// Splits the buffer into `count` equal slices (1 to `MAX_SLICES`).
+    pub fn set_slice_count(&mut self, count: usize) {
+        let count = count.clamp(1, MAX_SLICES);
+        if count != self.slice_count() {
+            self.update_slice_points(count);
+        }
+    }
+
+    fn update_slice_points(&mut self, count: usize) {
+        let len = self.buffer_len();
+        self.slice_points.clear();
+        self.slice_points
+            .extend((0..=count).map(|i| i * len / count));
+    }
+
+    pub fn slice_count(&self) -> usize {
+        self.slice_points.len() - 1
+    }
+
+    /// Frame range `(start, end)` of a slice; out-of-range indices use the last slice.
+    pub fn slice_bounds(&self, slice: usize) -> (f64, f64) {
+        let slice = slice.min(self.slice_count() - 1);
+        (
+            self.slice_points[slice] as f64,
+            self.slice_points[slice + 1] as f64,
+        )
+    }
+
     pub fn clear(&mut self) {
-        self.buffer.clear();
+        self.buffer = Arc::new(SampleBuffer::default());
+        self.update_slice_points(self.slice_count());
         self.position = 0.0;
+        self.direction = 1.0;
         self.srr_counter = 0.0;
         self.srr_hold_sample = 0.0;
+        self.srr_hold_sample_r = 0.0;
     }
 
     pub fn trigger(&mut self, start_position: f64) {
-        self.position = start_position.clamp(0.0, self.buffer.len() as f64);
+        self.trigger_with_direction(start_position, PlaybackDirection::Forward);
+    }
+
+    pub fn trigger_with_direction(&mut self, start_position: f64, direction: PlaybackDirection) {
+        self.position = start_position.clamp(0.0, self.buffer_len() as f64);
+        self.direction = direction.sign();
         self.srr_counter = 0.0;
         self.srr_hold_sample = 0.0;
+        self.srr_hold_sample_r = 0.0;
     }
 
     pub fn read_sample_linear(&mut self, pitch_ratio: f64) -> f32 {
-        if self.buffer.is_empty() {
-            return 0.0;
+        self.read_sample(pitch_ratio, InterpolationMode::Linear)
+    }
+
+    /// Reads one mono sample, averaging both sides of a stereo buffer.
+    pub fn read_sample(&mut self, pitch_ratio: f64, mode: InterpolationMode) -> f32 {
+        let (left, right) = self.read_frame(pitch_ratio, mode);
+        (left + right) * 0.5
+    }
+
+    /// Reads one stereo frame. Mono buffers return the same value on both sides.
+    pub fn read_frame(&mut self, pitch_ratio: f64, mode: InterpolationMode) -> (f32, f32) {
+        let Some(frame) = self.frame_at(self.position, pitch_ratio, mode) else {
+            return (0.0, 0.0);
+        };
+        self.position += pitch_ratio * self.direction;
+        frame
+    }
+
+    /// Interpolated frame at an arbitrary position without moving the play
+    /// head. None outside the buffer.
+    pub fn frame_at(
+        &self,
+        position: f64,
+        pitch_ratio: f64,
+        mode: InterpolationMode,
+    ) -> Option<(f32, f32)> {
+        Self::frame_in(&self.buffer, position, pitch_ratio, mode)
+    }
+
+    /// Like `frame_at`, reading `buffer` instead of the engine's own, e.g.
+    /// one the engine has since moved off.
+    pub fn frame_in(
+        buffer: &SampleBuffer,
+        position: f64,
+        pitch_ratio: f64,
+        mode: InterpolationMode,
+    ) -> Option<(f32, f32)> {
+        let len = buffer.frames();
+        let idx = position as usize;
+
+        if len == 0 || position < 0.0 || idx >= len {
+            return None;
         }
 
-        let len = self.buffer.len();
-        let idx = self.position as usize;
+        let frac = (position - idx as f64) as f32;
+        let idx = idx as isize;
+        let left = Self::interpolate(buffer, idx, frac, pitch_ratio, mode, 0);
+        let right = match buffer.format() {
+            BufferFormat::Mono12 => left,
+            BufferFormat::Stereo16 => Self::interpolate(buffer, idx, frac, pitch_ratio, mode, 1),
+        };
+        Some((left, right))
+    }
+
+    fn interpolate(
+        buffer: &SampleBuffer,
+        idx: isize,
+        frac: f32,
+        pitch_ratio: f64,
+        mode: InterpolationMode,
+        channel: usize,
+    ) -> f32 {
+        match mode {
+            InterpolationMode::DropSample => Self::sample_at(buffer, idx, channel),
+            InterpolationMode::Linear => {
+                let s0 = Self::sample_at(buffer, idx, channel);
+                let s1 = Self::sample_at(buffer, idx + 1, channel);
+                s0 + (s1 - s0) * frac
+            }
+            InterpolationMode::Cubic => hermite(
+                Self::sample_at(buffer, idx - 1, channel),
+                Self::sample_at(buffer, idx, channel),
+                Self::sample_at(buffer, idx + 1, channel),
+                Self::sample_at(buffer, idx + 2, channel),
+                frac,
+            ),
+            InterpolationMode::Sinc => Self::read_sinc(buffer, idx, frac, pitch_ratio, channel),
+        }
+    }
 
-        if idx >= len - 1 {
+    /// Folds the play position back into `[loop_start, loop_end)` once it has
+    /// run past the loop point it is heading towards. A voice still
+    /// approaching the loop from outside is left alone. Does nothing for
+    /// `LoopMode::Off`.
+    pub fn apply_loop(&mut self, loop_start: f64, loop_end: f64, mode: LoopMode) {
+        let loop_len = loop_end - loop_start;
+        if loop_len <= 0.0 {
+            return;
+        }
+
+        let forward = self.direction > 0.0;
+        let past_end = forward && self.position >= loop_end;
+        let past_start = !forward && self.position < loop_start;
+
+        match mode {
+            LoopMode::Off => {}
+            LoopMode::Loop => {
+                if past_end || past_start {
+                    self.position = loop_start + (self.position - loop_start).rem_euclid(loop_len);
+                }
+            }
+            LoopMode::PingPong => {
+                if past_end {
+                    self.position = (2.0 * loop_end - self.position).max(loop_start);
+                    self.direction = -1.0;
+                } else if past_start {
+                    self.position = (2.0 * loop_start - self.position).min(loop_end);
+                    self.direction = 1.0;
+                }
+            }
+        }
+    }
+
+    /// Returns the normalized sample of `channel` in frame `idx`, or silence
+    /// outside the buffer.
+    #[inline]
+    fn sample_at(buffer: &SampleBuffer, idx: isize, channel: usize) -> f32 {
+        if idx < 0 || idx as usize >= buffer.frames() {
             return 0.0;
         }
+        let format = buffer.format();
+        let value = buffer.get(idx as usize * format.channels() + channel);
+        format.normalize(value)
+    }
 
-        let frac = (self.position - idx as f64) as f32;
-        let s0: f32 = self.buffer[idx] as f32 / 32768.0;
-        let s1: f32 = self.buffer[idx + 1] as f32 / 32768.0;
-        let interpolated = s0 + (s1 - s0) * frac;
+    /// Windowed-sinc read around `idx + frac`. The cutoff drops below
+    /// Nyquist when pitching up so the kernel also acts as the anti-alias filter.
+    fn read_sinc(
+        buffer: &SampleBuffer,
+        idx: isize,
+        frac: f32,
+        pitch_ratio: f64,
+        channel: usize,
+    ) -> f32 {
+        let cutoff = (1.0 / pitch_ratio.abs().max(1.0)) as f32;
+        let half = SINC_HALF_TAPS as f32;
+        let mut sum = 0.0;
+        for k in (1 - SINC_HALF_TAPS)..=SINC_HALF_TAPS {
+            let x = k as f32 - frac;
+            let weight = cutoff * sinc(cutoff * x) * blackman(x / half);
+            sum += Self::sample_at(buffer, idx + k, channel) * weight;
+        }
+        sum
+    }
 
-        self.position += pitch_ratio;
+    /// Reads one frame through the machine's output stage. 12-bit buffers are
+    /// requantized to 12 bits; 16-bit buffers keep their full resolution.
+    pub fn read_frame_with_processing(
+        &mut self,
+        pitch_ratio: f64,
+        srr: i32,
+        mode: InterpolationMode,
+        dither: DitherMode,
+    ) -> (f32, f32) {
+        let (left, right) = self.read_frame(pitch_ratio, mode);
+        match self.format() {
+            BufferFormat::Mono12 => {
+                let quantized = apply_12bit_quantization(left);
+                let reduced = self.reduce_bit_depth(quantized, srr, dither);
+                let held = apply_sample_rate_reduction(
+                    reduced,
+                    srr,
+                    &mut self.srr_counter,
+                    &mut self.srr_hold_sample,
+                );
+                (held, held)
+            }
+            BufferFormat::Stereo16 => {
+                let left = self.reduce_bit_depth(left, srr, dither);
+                let right = self.reduce_bit_depth(right, srr, dither);
 
-        interpolated
+                // Both sides share one sample-and-hold clock
+                let mut counter_r = self.srr_counter;
+                let held_l = apply_sample_rate_reduction(
+                    left,
+                    srr,
+                    &mut self.srr_counter,
+                    &mut self.srr_hold_sample,
+                );
+                let held_r = apply_sample_rate_reduction(
+                    right,
+                    srr,
+                    &mut counter_r,
+                    &mut self.srr_hold_sample_r,
+                );
+                (held_l, held_r)
+            }
+        }
     }
 
-    pub fn read_sample_with_processing(&mut self, pitch_ratio: f64, srr: i32) -> f32 {
-        let raw_sample = self.read_sample_linear(pitch_ratio);
-        let quantized = apply_12bit_quantization(raw_sample);
-        apply_sample_rate_reduction(
-            quantized,
-            srr,
-            &mut self.srr_counter,
-            &mut self.srr_hold_sample,
-        )
+    /// Lowers the resolution of `sample` along the SRR bit-depth curve.
+    fn reduce_bit_depth(&mut self, sample: f32, srr: i32, dither: DitherMode) -> f32 {
+        if srr == 0 {
+            return sample;
+        }
+        let bits = srr_bit_depth(srr, self.format().bits());
+        let noise = match dither {
+            DitherMode::Off => 0.0,
+            DitherMode::Tpdf => self.next_noise() + self.next_noise(),
+        };
+        apply_bit_reduction(sample, bits, noise)
+    }
+
+    /// Uniform noise in [-0.5, 0.5)
+    fn next_noise(&mut self) -> f32 {
+        let mut x = self.dither_seed;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.dither_seed = x;
+        x as f32 / 4_294_967_296.0 - 0.5
     }
 
-    pub fn is_finished(&self, end_position: f64) -> bool {
-        self.position >= end_position || self.position >= self.buffer.len() as f64
+    /// Whether playback has left `[start_position, end_position)` in the
+    /// direction of travel.
+    pub fn is_finished(&self, start_position: f64, end_position: f64) -> bool {
+        if self.direction < 0.0 {
+            self.position < start_position || self.position < 0.0
+        } else {
+            self.position >= end_position || self.position >= self.buffer_len() as f64
+        }
     }
 
+    /// Buffer length in frames
     pub fn buffer_len(&self) -> usize {
-        self.buffer.len()
+        self.buffer.frames()
+    }
+
+    pub fn format(&self) -> BufferFormat {
+        self.buffer.format()
     }
 
     pub fn position(&self) -> f64 {
         self.position
     }
 
-    pub fn buffer(&self) -> &[i16] {
+    pub fn direction(&self) -> f64 {
+        self.direction
+    }
+
+    pub fn buffer(&self) -> &Arc<SampleBuffer> {
         &self.buffer
     }
 }
 
+/// Four-point, third-order Hermite interpolation between `y0` and `y1`.
+#[inline]
+fn hermite(y_m1: f32, y0: f32, y1: f32, y2: f32, frac: f32) -> f32 {
+    let c1 = 0.5 * (y1 - y_m1);
+    let c2 = y_m1 - 2.5 * y0 + 2.0 * y1 - 0.5 * y2;
+    let c3 = 0.5 * (y2 - y_m1) + 1.5 * (y0 - y1);
+    ((c3 * frac + c2) * frac + c1) * frac + y0
+}
+
+/// Normalized sinc: sin(pi x) / (pi x)
+#[inline]
+fn sinc(x: f32) -> f32 {
+    if x.abs() < 1e-6 {
+        return 1.0;
+    }
+    let px = std::f32::consts::PI * x;
+    px.sin() / px
+}
+
+/// Blackman window over t in [-1, 1], zero outside.
+#[inline]
+fn blackman(t: f32) -> f32 {
+    if t.abs() >= 1.0 {
+        return 0.0;
+    }
+    let pt = std::f32::consts::PI * t;
+    0.42 + 0.5 * pt.cos() + 0.08 * (2.0 * pt).cos()
+}
+
 /// Applies 12-bit quantization as per Machinedrum UW specs.
 /// Converts from 16-bit to 12-bit range, adding the characteristic grit.
 #[inline]
@@ -99,11 +563,30 @@ pub fn apply_12bit_quantization(sample: f32) -> f32 {
     clamped / 2048.0
 }
 
+/// Effective bit depth of a `base_bits` signal at the given SRR (0-127).
+/// Falls off with the square of SRR, from `base_bits` at 0 to 2 bits at 127,
+/// so low settings mostly lengthen the hold and the grit comes in at the top.
+#[inline]
+pub fn srr_bit_depth(srr: i32, base_bits: f32) -> f32 {
+    let srr_normalized = srr.clamp(0, 127) as f32 / 127.0;
+    base_bits - (base_bits - SRR_MIN_BITS) * srr_normalized * srr_normalized
+}
+
+/// Rounds a sample to `bits` of resolution. Fractional depths give
+/// intermediate step sizes. `dither` is added in LSBs before rounding.
+#[inline]
+pub fn apply_bit_reduction(sample: f32, bits: f32, dither: f32) -> f32 {
+    let scale = 2.0_f32.powf(bits - 1.0);
+    let quantized = (sample * scale + dither).round();
+    quantized.clamp(-scale, scale - 1.0) / scale
+}
+
 /// Applies sample rate reduction (SRR) as per Machinedrum UW specs.
-/// SRR 0-127: 0 = no reduction, 127 = extreme lo-fi (down to ~2-bit equivalent)
+/// SRR 0-127: 0 = no reduction, 127 = extreme lo-fi
 ///
 /// This implements a sample-and-hold effect where higher SRR values
 /// cause fewer sample updates, creating the lo-fi aliasing characteristic.
+/// The matching loss of resolution is applied by `apply_bit_reduction`.
 #[inline]
 pub fn apply_sample_rate_reduction(
     sample: f32,
@@ -133,7 +616,12 @@ pub fn apply_sample_rate_reduction(
 /// Converts a pitch parameter (0-127, center=64) to a playback ratio.
 /// 64 = 1.0x (original speed), 0 = 0.5x, 127 = ~2.0x
 pub fn pitch_to_ratio(pitch: i32) -> f64 {
-    let semitones = (pitch - 64) as f64;
+    semitones_to_ratio((pitch - 64) as f64)
+}
+
+/// Converts an equal-tempered interval in semitones to a playback ratio.
+#[inline]
+pub fn semitones_to_ratio(semitones: f64) -> f64 {
     2.0_f64.powf(semitones / 12.0)
 }
 
@@ -162,6 +650,81 @@ mod tests {
         assert!((quantized - precise).abs() <= step_size);
     }
 
+    #[test]
+    fn test_bit_reduction_at_12_bits_matches_quantizer() {
+        for sample in [0.0, 0.12345678, -0.5, 0.999, -1.0] {
+            assert_eq!(
+                apply_bit_reduction(sample, 12.0, 0.0),
+                apply_12bit_quantization(sample)
+            );
+        }
+    }
+
+    #[test]
+    fn test_srr_bit_depth_curve() {
+        assert_eq!(srr_bit_depth(0, 12.0), 12.0);
+        assert_eq!(srr_bit_depth(127, 12.0), 2.0);
+        assert_eq!(srr_bit_depth(127, 16.0), 2.0);
+        // Square-law: half SRR only takes a quarter of the range
+        assert!((srr_bit_depth(64, 12.0) - 9.46).abs() < 0.01);
+        for srr in 1..=127 {
+            assert!(srr_bit_depth(srr, 12.0) < srr_bit_depth(srr - 1, 12.0));
+        }
+    }
+
+    #[test]
+    fn test_2bit_reduction_leaves_four_levels() {
+        let mut levels: Vec<f32> = (-100..=100)
+            .map(|i| apply_bit_reduction(i as f32 / 100.0, 2.0, 0.0))
+            .collect();
+        levels.dedup();
+        assert_eq!(levels, vec![-1.0, -0.5, 0.0, 0.5]);
+    }
+
+    #[test]
+    fn test_srr_reduces_bit_depth_in_engine() {
+        let mut engine = SampleEngine::new(44100.0);
+        engine.load_buffer(vec![300; 16]);
+        engine.trigger(0.0);
+        let (left, _) =
+            engine.read_frame_with_processing(1.0, 127, InterpolationMode::Linear, DitherMode::Off);
+        // 300/2048 rounds to zero at 2 bits
+        assert_eq!(left, 0.0);
+    }
+
+    #[test]
+    fn test_dither_averages_to_signal() {
+        let mut engine = SampleEngine::new(44100.0);
+        engine.load_buffer(vec![300; 4000]);
+        engine.trigger(0.0);
+        let mut sum = 0.0;
+        let mut distinct = Vec::new();
+        for _ in 0..4000 {
+            // SRR 127 holds each value for 64 samples, so average the held output
+            let (left, right) = engine.read_frame_with_processing(
+                1.0,
+                127,
+                InterpolationMode::Linear,
+                DitherMode::Tpdf,
+            );
+            assert_eq!(left, right);
+            sum += left;
+            if !distinct.contains(&left) {
+                distinct.push(left);
+            }
+        }
+        let mean = sum / 4000.0;
+        assert!(distinct.len() > 1);
+        assert!((mean - 300.0 / 2048.0).abs() < 0.08);
+    }
+
+    #[test]
+    fn test_dither_mode_from_param() {
+        assert_eq!(DitherMode::from_param(0), DitherMode::Off);
+        assert_eq!(DitherMode::from_param(1), DitherMode::Tpdf);
+        assert_eq!(DitherMode::from_param(5), DitherMode::Off);
+    }
+
     #[test]
     fn test_srr_zero_passes_through() {
         let mut counter = 0.0;
@@ -197,6 +760,13 @@ mod tests {
         assert!((ratio - 0.5).abs() < 0.01);
     }
 
+    #[test]
+    fn test_semitones_to_ratio() {
+        assert!((semitones_to_ratio(0.0) - 1.0).abs() < 1e-9);
+        assert!((semitones_to_ratio(-12.0) - 0.5).abs() < 1e-9);
+        assert!((semitones_to_ratio(7.0) - 1.4983).abs() < 1e-4);
+    }
+
     #[test]
     fn test_linear_interpolation() {
         let mut engine = SampleEngine::new(44100.0);
@@ -206,4 +776,255 @@ mod tests {
         let s1 = engine.read_sample_linear(0.5);
         assert!(s1 >= 0.0 && s1 < 0.3);
     }
+
+    #[test]
+    fn test_linear_interpolation_of_12bit_values() {
+        let mut engine = SampleEngine::new(44100.0);
+        engine.load_buffer(vec![0, 1024, 2047]);
+        engine.trigger(0.0);
+
+        assert_eq!(engine.read_sample_linear(0.5), 0.0);
+        // Halfway between 0 and 1024/2048
+        let s1 = engine.read_sample_linear(0.5);
+        assert!((s1 - 0.25).abs() < 0.001);
+        let s2 = engine.read_sample_linear(0.5);
+        assert!((s2 - 0.5).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_last_frame_plays_without_reading_past_len() {
+        // Storage past the written length still holds older samples
+        let buffer = SampleBuffer::new(4);
+        for _ in 0..4 {
+            buffer.push(2047);
+        }
+        buffer.clear();
+        for value in [0, 512, 1024] {
+            buffer.push(value);
+        }
+        let mut engine = SampleEngine::new(44100.0);
+        engine.set_shared_buffer(Arc::new(buffer));
+        engine.trigger(0.0);
+
+        let played: Vec<f32> = (0..4)
+            .map(|_| engine.read_sample(1.0, InterpolationMode::DropSample))
+            .collect();
+        assert_eq!(played, vec![0.0, 0.25, 0.5, 0.0]);
+
+        // Past the last frame, interpolation fades to silence
+        let (left, _) = engine
+            .frame_at(2.5, 1.0, InterpolationMode::Linear)
+            .unwrap();
+        assert!((left - 0.25).abs() < 0.001);
+        assert!(engine
+            .frame_at(3.0, 1.0, InterpolationMode::Linear)
+            .is_none());
+    }
+
+    #[test]
+    fn test_interpolation_mode_from_param() {
+        assert_eq!(
+            InterpolationMode::from_param(0),
+            InterpolationMode::DropSample
+        );
+        assert_eq!(InterpolationMode::from_param(1), InterpolationMode::Linear);
+        assert_eq!(InterpolationMode::from_param(2), InterpolationMode::Cubic);
+        assert_eq!(InterpolationMode::from_param(3), InterpolationMode::Sinc);
+        assert_eq!(InterpolationMode::from_param(99), InterpolationMode::Linear);
+    }
+
+    #[test]
+    fn test_drop_sample_holds_lower_sample() {
+        let mut engine = SampleEngine::new(44100.0);
+        engine.load_buffer(vec![0, 1024, 2047]);
+        engine.trigger(0.0);
+
+        let s0 = engine.read_sample(1.25, InterpolationMode::DropSample);
+        assert_eq!(s0, 0.0);
+        let s1 = engine.read_sample(1.25, InterpolationMode::DropSample);
+        assert!((s1 - 0.5).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_cubic_and_sinc_pass_through_sample_points() {
+        let buffer: Vec<i16> = (0..64)
+            .map(|i| ((i as f32 * 0.3).sin() * 2000.0) as i16)
+            .collect();
+
+        for mode in [InterpolationMode::Cubic, InterpolationMode::Sinc] {
+            let mut engine = SampleEngine::new(44100.0);
+            engine.load_buffer(buffer.clone());
+            engine.trigger(20.0);
+            for (i, &raw) in buffer.iter().enumerate().skip(20).take(10) {
+                let expected = raw as f32 / 2048.0;
+                let sample = engine.read_sample(1.0, mode);
+                assert!((sample - expected).abs() < 0.001, "{:?} at {}", mode, i);
+            }
+        }
+    }
+
+    #[test]
+    fn test_sinc_attenuates_near_nyquist_when_pitched_up() {
+        // Alternating full-scale signal sits at Nyquist
+        let buffer: Vec<i16> = (0..256)
+            .map(|i| if i % 2 == 0 { 2000 } else { -2000 })
+            .collect();
+
+        let mut linear = SampleEngine::new(44100.0);
+        linear.load_buffer(buffer.clone());
+        linear.trigger(64.25);
+        let mut sinc = SampleEngine::new(44100.0);
+        sinc.load_buffer(buffer);
+        sinc.trigger(64.25);
+
+        let mut linear_peak: f32 = 0.0;
+        let mut sinc_peak: f32 = 0.0;
+        for _ in 0..32 {
+            linear_peak = linear_peak.max(linear.read_sample(2.0, InterpolationMode::Linear).abs());
+            sinc_peak = sinc_peak.max(sinc.read_sample(2.0, InterpolationMode::Sinc).abs());
+        }
+        assert!(sinc_peak < linear_peak * 0.5);
+    }
+
+    #[test]
+    fn test_loop_mode_from_param() {
+        assert_eq!(LoopMode::from_param(0), LoopMode::Off);
+        assert_eq!(LoopMode::from_param(1), LoopMode::Loop);
+        assert_eq!(LoopMode::from_param(2), LoopMode::PingPong);
+    }
+
+    #[test]
+    fn test_forward_loop_wraps_to_loop_start() {
+        let mut engine = SampleEngine::new(44100.0);
+        engine.load_buffer(vec![0; 100]);
+        engine.trigger(0.0);
+
+        for _ in 0..90 {
+            engine.read_sample(1.0, InterpolationMode::Linear);
+            engine.apply_loop(40.0, 80.0, LoopMode::Loop);
+            assert!(engine.position() < 80.0);
+        }
+        assert!((engine.position() - 50.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_ping_pong_reverses_at_loop_points() {
+        let mut engine = SampleEngine::new(44100.0);
+        engine.load_buffer(vec![0; 100]);
+        engine.trigger(70.0);
+
+        for _ in 0..15 {
+            engine.read_sample(1.0, InterpolationMode::Linear);
+            engine.apply_loop(60.0, 80.0, LoopMode::PingPong);
+        }
+        assert_eq!(engine.direction(), -1.0);
+        assert!((engine.position() - 75.0).abs() < 1e-9);
+
+        for _ in 0..20 {
+            engine.read_sample(1.0, InterpolationMode::Linear);
+            engine.apply_loop(60.0, 80.0, LoopMode::PingPong);
+        }
+        assert_eq!(engine.direction(), 1.0);
+        assert!(engine.position() >= 60.0 && engine.position() < 80.0);
+    }
+
+    #[test]
+    fn test_reverse_playback_reads_backwards() {
+        let mut engine = SampleEngine::new(44100.0);
+        engine.load_buffer(vec![0, 512, 1024, 1536]);
+        engine.trigger_with_direction(3.0, PlaybackDirection::Reverse);
+
+        let s0 = engine.read_sample(1.0, InterpolationMode::Linear);
+        let s1 = engine.read_sample(1.0, InterpolationMode::Linear);
+        assert!((s0 - 0.75).abs() < 0.001);
+        assert!((s1 - 0.5).abs() < 0.001);
+        assert!(!engine.is_finished(1.0, 4.0));
+
+        engine.read_sample(1.0, InterpolationMode::Linear);
+        assert!(engine.is_finished(1.0, 4.0));
+    }
+
+    #[test]
+    fn test_forward_voice_before_loop_start_is_not_folded() {
+        let mut engine = SampleEngine::new(44100.0);
+        engine.load_buffer(vec![0; 100]);
+        engine.trigger(10.0);
+
+        for mode in [LoopMode::Loop, LoopMode::PingPong] {
+            engine.apply_loop(40.0, 80.0, mode);
+            assert!((engine.position() - 10.0).abs() < 1e-9);
+            assert_eq!(engine.direction(), 1.0);
+        }
+    }
+
+    #[test]
+    fn test_reverse_loop_wraps_to_loop_end() {
+        let mut engine = SampleEngine::new(44100.0);
+        engine.load_buffer(vec![0; 100]);
+        engine.trigger_with_direction(79.0, PlaybackDirection::Reverse);
+
+        for _ in 0..45 {
+            engine.read_sample(1.0, InterpolationMode::Linear);
+            engine.apply_loop(40.0, 80.0, LoopMode::Loop);
+            assert!(engine.position() >= 40.0 && engine.position() < 80.0);
+        }
+        assert_eq!(engine.direction(), -1.0);
+    }
+
+    #[test]
+    fn test_buffer_format_round_trip() {
+        assert_eq!(BufferFormat::Mono12.quantize(1.0), 2047);
+        assert_eq!(BufferFormat::Mono12.quantize(-1.0), -2048);
+        assert_eq!(BufferFormat::Stereo16.quantize(1.0), 32767);
+        assert!((BufferFormat::Mono12.normalize(1024) - 0.5).abs() < 0.001);
+        assert!((BufferFormat::Stereo16.normalize(16384) - 0.5).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_stereo_buffer_keeps_image_and_resolution() {
+        let mut engine = SampleEngine::new(44100.0);
+        // Left ramps up, right is a constant 16-bit value below 12-bit resolution
+        engine.load_buffer_with_format(vec![0, 5, 8192, 5, 16384, 5], BufferFormat::Stereo16);
+        engine.trigger(0.0);
+        assert_eq!(engine.buffer_len(), 3);
+
+        let _ =
+            engine.read_frame_with_processing(1.0, 0, InterpolationMode::Linear, DitherMode::Off);
+        let (left, right) =
+            engine.read_frame_with_processing(1.0, 0, InterpolationMode::Linear, DitherMode::Off);
+        assert!((left - 0.25).abs() < 0.001);
+        assert!(right > 0.0 && right < 1.0 / 2048.0);
+    }
+
+    #[test]
+    fn test_mono_buffer_plays_at_full_scale() {
+        let mut engine = SampleEngine::new(44100.0);
+        engine.load_buffer(vec![2047, 2047]);
+        engine.trigger(0.0);
+        let (left, right) =
+            engine.read_frame_with_processing(1.0, 0, InterpolationMode::Linear, DitherMode::Off);
+        assert!(left > 0.99);
+        assert_eq!(left, right);
+    }
+
+    #[test]
+    fn test_slices_split_buffer_evenly() {
+        let mut engine = SampleEngine::new(44100.0);
+        engine.load_buffer(vec![0; 1000]);
+        assert_eq!(engine.slice_count(), 1);
+        assert_eq!(engine.slice_bounds(0), (0.0, 1000.0));
+
+        engine.set_slice_count(4);
+        assert_eq!(engine.slice_bounds(0), (0.0, 250.0));
+        assert_eq!(engine.slice_bounds(3), (750.0, 1000.0));
+        assert_eq!(engine.slice_bounds(9), (750.0, 1000.0));
+
+        // Slices follow a newly loaded buffer
+        engine.load_buffer(vec![0; 400]);
+        assert_eq!(engine.slice_count(), 4);
+        assert_eq!(engine.slice_bounds(1), (100.0, 200.0));
+
+        engine.set_slice_count(1000);
+        assert_eq!(engine.slice_count(), MAX_SLICES);
+    }
 }
@@ -59,6 +59,8 @@ pub fn create(
                             .font_size(10.0)
                             .class("led-label");
                         Element::new(cx).class("led-indicator");
+                        ParamButton::new(cx, EditorData::params, |p| &p.record_switch)
+                            .with_label("REC");
                     })
                     .class("led-section");
                 })
@@ -1,6 +1,8 @@
 use nih_plug::prelude::*;
 use std::sync::Arc;
 
+#[cfg(test)]
+mod alloc_check;
 mod dsp;
 mod editor;
 mod machines;
@@ -8,29 +10,77 @@ mod params;
 pub mod standalone;
 
 use dsp::filter::ResonantFilter;
-use machines::ram_play::{RamPlay, RamPlayParams as RamPlayMachineParams};
-use machines::ram_record::RamRecord;
+use dsp::mixer::{ChannelStrip, Mixer, AUX_BUSES};
+use dsp::resampler::{FrameQueue, InputResampler, OutputResampler, ENGINE_SAMPLE_RATE};
+use dsp::sample_buffer::SampleBuffer;
+use machines::memory::{MemoryPolicy, HARDWARE_MEMORY_BYTES};
+use machines::ram_play::{slice_for_note, RamPlay, RamPlayParams as RamPlayMachineParams};
+use machines::ram_record::{RamRecord, RamRecordParams as RamRecordMachineParams, ResampleTap};
 use params::UltrawaveParams;
 
+/// Main pair followed by one pair per aux bus
+const MULTI_OUT_CHANNELS: usize = 2 + 2 * AUX_BUSES;
+
+/// Work handed off the audio thread
+pub enum UltrawaveTask {
+    /// A buffer the players moved off, freed here so the audio thread
+    /// never deallocates
+    Free(Arc<SampleBuffer>),
+}
+
 pub struct Ultrawave {
     params: Arc<UltrawaveParams>,
     editor_state: Arc<nih_plug_vizia::ViziaState>,
     sample_rate: f32,
     ram_record: RamRecord,
     ram_play: RamPlay,
-    filter: ResonantFilter,
+    /// The main mix filter, run on each channel's share so resampling can
+    /// leave a channel out after filtering. Being linear, the shares still
+    /// sum to the filtered mix.
+    filters: [ResonantFilter; 8],
+    mixer: Mixer,
+    /// Converts RamPlay output from the engine clock to the host rate
+    play_resampler: OutputResampler<2>,
+    /// Same for the main mix and aux buses together when aux outputs are active
+    multi_out_resampler: OutputResampler<MULTI_OUT_CHANNELS>,
+    /// Whether the host picked the layout with aux output ports
+    aux_outputs: bool,
+    /// Converts main and sidechain input from the host rate to the engine clock
+    record_resampler: InputResampler<4>,
+    /// Engine-rate input frames waiting for the render path
+    input_queue: FrameQueue<4>,
+    /// Last seen state of the editor record switch
+    record_switch: bool,
+    /// Whether the RAM machines currently run at `ENGINE_SAMPLE_RATE`
+    engine_clock: bool,
+    /// Host song position in beats at the start of the block
+    block_position: Option<f64>,
+    beats_per_sample: f64,
 }
 
 impl Default for Ultrawave {
     fn default() -> Self {
         let sample_rate = 44100.0;
+        let ram_record = RamRecord::new(sample_rate);
+        let mut ram_play = RamPlay::new(sample_rate);
+        ram_play.share_all_buffers(ram_record.get_all_buffers());
         Self {
             params: Arc::new(UltrawaveParams::default()),
             editor_state: editor::default_state(),
             sample_rate,
-            ram_record: RamRecord::new(sample_rate),
-            ram_play: RamPlay::new(sample_rate),
-            filter: ResonantFilter::new(sample_rate),
+            ram_record,
+            ram_play,
+            filters: std::array::from_fn(|_| ResonantFilter::new(sample_rate)),
+            mixer: Mixer::new(),
+            play_resampler: OutputResampler::new(ENGINE_SAMPLE_RATE, sample_rate),
+            multi_out_resampler: OutputResampler::new(ENGINE_SAMPLE_RATE, sample_rate),
+            aux_outputs: false,
+            record_resampler: InputResampler::new(sample_rate, ENGINE_SAMPLE_RATE),
+            input_queue: FrameQueue::new(),
+            record_switch: false,
+            engine_clock: false,
+            block_position: None,
+            beats_per_sample: 0.0,
         }
     }
 }
@@ -41,20 +91,40 @@ impl Plugin for Ultrawave {
     const URL: &'static str = "https://github.com/EphemeralDSP/ultrawave";
     const EMAIL: &'static str = "";
     const VERSION: &'static str = env!("CARGO_PKG_VERSION");
-    const AUDIO_IO_LAYOUTS: &'static [AudioIOLayout] = &[AudioIOLayout {
-        main_input_channels: None,
-        main_output_channels: NonZeroU32::new(2),
-        aux_input_ports: &[],
-        aux_output_ports: &[],
-        names: PortNames::const_default(),
-    }];
-
-    const MIDI_INPUT: MidiConfig = MidiConfig::Basic;
+    const AUDIO_IO_LAYOUTS: &'static [AudioIOLayout] = &[
+        AudioIOLayout {
+            main_input_channels: NonZeroU32::new(2),
+            main_output_channels: NonZeroU32::new(2),
+            aux_input_ports: &[new_nonzero_u32(2)],
+            aux_output_ports: &[],
+            names: PortNames {
+                aux_inputs: &["Sidechain"],
+                ..PortNames::const_default()
+            },
+        },
+        // Channels routed to an aux bus come out on their own stereo port
+        AudioIOLayout {
+            main_input_channels: NonZeroU32::new(2),
+            main_output_channels: NonZeroU32::new(2),
+            aux_input_ports: &[new_nonzero_u32(2)],
+            aux_output_ports: &[new_nonzero_u32(2); AUX_BUSES],
+            names: PortNames {
+                layout: Some("Multi-Out"),
+                aux_inputs: &["Sidechain"],
+                aux_outputs: &[
+                    "Bus 1", "Bus 2", "Bus 3", "Bus 4", "Bus 5", "Bus 6", "Bus 7", "Bus 8",
+                ],
+                ..PortNames::const_default()
+            },
+        },
+    ];
+
+    const MIDI_INPUT: MidiConfig = MidiConfig::MidiCCs;
     const MIDI_OUTPUT: MidiConfig = MidiConfig::None;
     const SAMPLE_ACCURATE_AUTOMATION: bool = true;
 
     type SysExMessage = ();
-    type BackgroundTask = ();
+    type BackgroundTask = UltrawaveTask;
 
     fn params(&self) -> Arc<dyn Params> {
         self.params.clone()
@@ -64,53 +134,76 @@ impl Plugin for Ultrawave {
         editor::create(self.params.clone(), self.editor_state.clone())
     }
 
+    fn filter_state(state: &mut PluginState) {
+        // Volume and pan were top-level params before the mixer; keep them
+        // on channel 1 for sessions saved back then
+        for (old, new) in [("vol", "vol_1"), ("pan", "pan_1")] {
+            if let Some(value) = state.params.remove(old) {
+                state.params.entry(new.to_string()).or_insert(value);
+            }
+        }
+    }
+
     fn initialize(
         &mut self,
-        _audio_io_layout: &AudioIOLayout,
+        audio_io_layout: &AudioIOLayout,
         buffer_config: &BufferConfig,
-        _context: &mut impl InitContext<Self>,
+        context: &mut impl InitContext<Self>,
     ) -> bool {
-        self.sample_rate = buffer_config.sample_rate;
-        self.ram_record.set_sample_rate(buffer_config.sample_rate);
-        self.ram_play.set_sample_rate(buffer_config.sample_rate);
-        self.filter.set_sample_rate(buffer_config.sample_rate);
+        self.prepare(
+            buffer_config.sample_rate,
+            !audio_io_layout.aux_output_ports.is_empty(),
+        );
+        context.set_latency_samples(self.latency());
         true
     }
 
+    fn reset(&mut self) {
+        self.play_resampler.reset();
+        self.multi_out_resampler.reset();
+        self.record_resampler.reset();
+    }
+
     fn process(
         &mut self,
         buffer: &mut Buffer,
-        _aux: &mut AuxiliaryBuffers,
+        aux: &mut AuxiliaryBuffers,
         context: &mut impl ProcessContext<Self>,
     ) -> ProcessStatus {
-        while let Some(event) = context.next_event() {
-            match event {
-                NoteEvent::NoteOn { velocity, .. } => {
-                    let chan = self.params.channel.value() as usize;
-                    if self.ram_record.buffer_len(chan) > 0 {
-                        let play_params = RamPlayMachineParams {
-                            strt: self.params.play.strt.value(),
-                            end: self.params.play.end.value(),
-                            pitch: self.params.play.pitch.value(),
-                            hold: self.params.play.hold.value(),
-                            dec: self.params.play.dec.value(),
-                            rtrg: self.params.play.rtrg.value(),
-                            rtim: self.params.play.rtim.value(),
-                            srr: self.params.play.srr.value(),
-                            vol: ((velocity * 127.0) as i32).min(127),
-                        };
-                        self.ram_play
-                            .load_buffer(self.ram_record.get_buffer(chan), chan);
-                        self.ram_play.trigger(&play_params, chan);
-                    }
-                }
-                NoteEvent::NoteOff { .. } => {
-                    let chan = self.params.channel.value() as usize;
-                    self.ram_play.stop(chan);
-                }
-                _ => {}
+        let engine_clock = self.params.clock.value() == 1;
+        if engine_clock != self.engine_clock {
+            self.set_engine_clock(engine_clock);
+            context.set_latency_samples(self.latency());
+        }
+
+        let transport = context.transport();
+        let tempo = transport.tempo.unwrap_or(120.0);
+        let beats_per_bar = match (transport.time_sig_numerator, transport.time_sig_denominator) {
+            (Some(numerator), Some(denominator)) if denominator > 0 => {
+                numerator as f64 * 4.0 / denominator as f64
+            }
+            _ => 4.0,
+        };
+        self.ram_record.set_tempo(tempo, beats_per_bar);
+        self.block_position = transport.pos_beats();
+        self.beats_per_sample = tempo / 60.0 / self.sample_rate as f64;
+
+        self.set_memory();
+
+        let record_switch = self.params.record_switch.value() == 1;
+        if record_switch != self.record_switch {
+            self.record_switch = record_switch;
+            let chan = self.params.channel.value() as usize;
+            if record_switch {
+                self.start_recording(chan, 0);
+            } else {
+                self.ram_record.stop_recording(chan);
             }
         }
+        // Level knobs edit the selected channel; others keep what they started with
+        let record_params = self.record_params();
+        self.ram_record
+            .set_params(&record_params, self.params.channel.value() as usize);
 
         let gain = nih_plug::util::db_to_gain(self.params.gain.value());
 
@@ -119,25 +212,342 @@ impl Plugin for Ultrawave {
             20.0 + (self.params.fltf.value() as f32 / 127.0) * (self.sample_rate * 0.45 - 20.0);
         let filter_resonance = self.params.fltq.value() as f32 / 127.0;
         let filter_mode = dsp::filter::FilterMode::from_param(self.params.fltw.value());
-        self.filter
-            .set_params(filter_freq, filter_resonance, filter_mode);
+        for filter in &mut self.filters {
+            filter.set_params(filter_freq, filter_resonance, filter_mode);
+        }
 
-        for channel_samples in buffer.iter_samples() {
-            let chan = self.params.channel.value() as usize;
-            let sample_out = self.ram_play.process(chan);
-            let (left, right) = self.filter.process_stereo(sample_out, sample_out);
+        let strips = std::array::from_fn(|chan| {
+            let strip = &self.params.mixer[chan];
+            ChannelStrip {
+                vol: strip.vol.value(),
+                pan: strip.pan.value(),
+                mute: strip.mute.value(),
+                solo: strip.solo.value(),
+                main: strip.main.value(),
+                bus: strip.bus.value(),
+            }
+        });
+        self.mixer.set_strips(&strips);
+
+        let mut next_event = context.next_event();
+        for (sample_idx, mut channel_samples) in buffer.iter_samples().enumerate() {
+            // Apply events due at this sample before rendering it
+            while let Some(event) = next_event {
+                if event.timing() > sample_idx as u32 {
+                    break;
+                }
+                self.handle_event(event);
+                next_event = context.next_event();
+            }
+
+            // Main input L/R followed by sidechain L/R
+            let mut input = [0.0; 4];
+            if self.ram_record.is_any_recording() {
+                for (idx, sample) in channel_samples.iter_mut().take(2).enumerate() {
+                    input[idx] = *sample;
+                }
+                if let Some(sidechain) = aux.inputs.first() {
+                    let sidechain = sidechain.as_slice_immutable();
+                    if let Some(left) = sidechain.first() {
+                        input[2] = left[sample_idx];
+                        input[3] = sidechain.get(1).map_or(input[2], |right| right[sample_idx]);
+                    }
+                }
+                if self.engine_clock {
+                    let input_queue = &mut self.input_queue;
+                    self.record_resampler
+                        .push_frame(input, |frame| input_queue.push(frame));
+                }
+            } else {
+                self.input_queue.clear();
+            }
+
+            let ((left, right), buses) = self.render_frame(input);
 
             let mut out_idx = 0;
             for sample in channel_samples {
                 *sample = if out_idx == 0 { left } else { right } * gain;
                 out_idx += 1;
             }
+
+            for (output, (bus_l, bus_r)) in aux.outputs.iter_mut().zip(buses) {
+                let bus = output.as_slice();
+                bus[0][sample_idx] = bus_l * gain;
+                bus[1][sample_idx] = bus_r * gain;
+            }
         }
+
+        self.ram_play
+            .release_retired(|buffer| context.execute_background(UltrawaveTask::Free(buffer)));
         ProcessStatus::Normal
     }
 
     fn task_executor(&mut self) -> TaskExecutor<Self> {
-        Box::new(|_task| {})
+        Box::new(|task| match task {
+            UltrawaveTask::Free(buffer) => drop(buffer),
+        })
+    }
+}
+
+impl Ultrawave {
+    /// Allocates everything `process` needs for a host sample rate
+    fn prepare(&mut self, sample_rate: f32, aux_outputs: bool) {
+        self.sample_rate = sample_rate;
+        self.play_resampler = OutputResampler::new(ENGINE_SAMPLE_RATE, sample_rate);
+        self.multi_out_resampler = OutputResampler::new(ENGINE_SAMPLE_RATE, sample_rate);
+        self.aux_outputs = aux_outputs;
+        self.record_resampler = InputResampler::new(sample_rate, ENGINE_SAMPLE_RATE);
+        // Room for either clock, so switching it never allocates. The
+        // sample memory pool is sized by the budget.
+        self.set_memory();
+        self.ram_record
+            .allocate(sample_rate.max(ENGINE_SAMPLE_RATE));
+        self.ram_play
+            .share_all_buffers(self.ram_record.get_all_buffers());
+        // Not on the audio thread, so old buffers can be freed here
+        self.ram_play.release_retired(drop);
+        self.set_engine_clock(self.params.clock.value() == 1);
+    }
+
+    fn set_memory(&mut self) {
+        let budget = if self.params.md_memory.value() == 1 {
+            HARDWARE_MEMORY_BYTES
+        } else {
+            self.params.memory_size.value() as usize * 1024 * 1024
+        };
+        self.ram_record.set_memory(
+            budget,
+            MemoryPolicy::from_param(self.params.memory_full.value()),
+        );
+    }
+
+    fn handle_event(&mut self, event: PluginNoteEvent<Self>) {
+        let timing = event.timing();
+        match event {
+            NoteEvent::NoteOn {
+                channel,
+                note,
+                velocity,
+                ..
+            } => {
+                let chan = self.note_channel(channel);
+                if note as i32 == self.params.record_note.value() {
+                    self.start_recording(chan, timing);
+                    return;
+                }
+                let slices = self.params.play.slices.value();
+                let Some(slice) = slice_for_note(note, self.params.play.slice_base.value(), slices)
+                else {
+                    return;
+                };
+                // Notes select slices while slicing, so only key-track whole buffers
+                let key = if slices <= 1 && self.params.play.ktrk.value() == 1 {
+                    note as i32 - self.params.play.root.value()
+                } else {
+                    0
+                };
+                if self.ram_record.buffer_len(chan) > 0 {
+                    let play_params = RamPlayMachineParams {
+                        strt: self.params.play.strt.value(),
+                        end: self.params.play.end.value(),
+                        pitch: self.params.play.pitch.value(),
+                        hold: self.params.play.hold.value(),
+                        dec: self.params.play.dec.value(),
+                        rtrg: self.params.play.rtrg.value(),
+                        rtim: self.params.play.rtim.value(),
+                        srr: self.params.play.srr.value(),
+                        vol: ((velocity * 127.0) as i32).min(127),
+                        intp: self.params.play.intp.value(),
+                        loop_mode: self.params.play.loop_mode.value(),
+                        lstr: self.params.play.lstr.value(),
+                        plbk: self.params.play.plbk.value(),
+                        slices,
+                        slice,
+                        dith: self.params.play.dith.value(),
+                        key,
+                        tune: self.params.play.tune.value(),
+                        bend: self.params.play.bend.value(),
+                        dclk: self.params.play.dclk.value(),
+                        atk: self.params.play.atk.value(),
+                        rel: self.params.play.rel.value(),
+                        dcrv: self.params.play.dcrv.value(),
+                        envm: self.params.play.envm.value(),
+                        poly: self.params.play.poly.value(),
+                        voices: self.params.play.voices.value(),
+                        steal: self.params.play.steal.value(),
+                    };
+                    self.ram_play.trigger_note(&play_params, chan, note);
+                }
+            }
+            NoteEvent::MidiPitchBend { channel, value, .. } => {
+                let chan = self.note_channel(channel);
+                self.ram_play.set_pitch_bend(value * 2.0 - 1.0, chan);
+            }
+            NoteEvent::NoteOff { channel, note, .. } => {
+                let chan = self.note_channel(channel);
+                if note as i32 == self.params.record_note.value() {
+                    self.ram_record.stop_recording(chan);
+                } else {
+                    self.ram_play.release_note(chan, note);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// RAM channel a note event plays on, following the Note Routing param
+    fn note_channel(&self, midi_channel: u8) -> usize {
+        if self.params.note_routing.value() == 1 {
+            (midi_channel as usize).min(7)
+        } else {
+            self.params.channel.value() as usize
+        }
+    }
+
+    /// Song position in beats `offset` samples into the current block
+    fn song_position(&self, offset: u32) -> Option<f64> {
+        self.block_position
+            .map(|beats| beats + offset as f64 * self.beats_per_sample)
+    }
+
+    /// Starts recording `chan` from an event `offset` samples into the block
+    fn start_recording(&mut self, chan: usize, offset: u32) {
+        let record_params = self.record_params();
+        self.ram_record
+            .set_song_position(self.song_position(offset));
+        self.ram_record.start_recording(&record_params, true, chan);
+    }
+
+    fn record_params(&self) -> RamRecordMachineParams {
+        RamRecordMachineParams {
+            mlev: self.params.record.mlev.value(),
+            mbal: self.params.record.mbal.value(),
+            ilev: self.params.record.ilev.value(),
+            ibal: self.params.record.ibal.value(),
+            rec_len: self.params.record.rec_len.value(),
+            rec_rate: self.params.record.rec_rate.value(),
+            fmt: self.params.record.fmt.value(),
+            trig: self.params.record.trig.value(),
+            thr: self.params.record.thr.value(),
+            prer: self.params.record.prer.value(),
+            hldo: self.params.record.hldo.value(),
+            asto: self.params.record.asto.value(),
+            dub: self.params.record.dub.value(),
+            fdbk: self.params.record.fdbk.value(),
+            circ: self.params.record.circ.value(),
+            sync: self.params.record.sync.value(),
+            slen: self.params.record.slen.value(),
+            qnt: self.params.record.qnt.value(),
+            plen: self.params.record.plen.value(),
+            src: self.params.record.src.value(),
+            xslf: self.params.record.xslf.value(),
+        }
+    }
+
+    /// Renders one host-rate frame of the main mix and, with aux outputs
+    /// active, the aux buses. `input` is the host input frame (main L/R,
+    /// sidechain L/R) for recording.
+    fn render_frame(&mut self, input: [f32; 4]) -> ((f32, f32), [(f32, f32); AUX_BUSES]) {
+        let aux_outputs = self.aux_outputs;
+        let engine_clock = self.engine_clock;
+        let ram_play = &mut self.ram_play;
+        let ram_record = &mut self.ram_record;
+        let input_queue = &mut self.input_queue;
+        let mixer = &self.mixer;
+        let filters = &mut self.filters;
+
+        // One frame at the machine rate. Recording happens here too, so
+        // resampling the mix stays sample-locked to playback.
+        let mut render = || {
+            let channels = ram_play.process_channels();
+            // The filter sits on the main mix; aux buses leave dry
+            let dry = mixer.main_contributions(&channels);
+            let contributions: [(f32, f32); 8] = std::array::from_fn(|channel| {
+                let (left, right) = dry[channel];
+                filters[channel].process_stereo(left, right)
+            });
+            let (main_l, main_r) = contributions
+                .iter()
+                .fold((0.0, 0.0), |(left, right), (l, r)| (left + l, right + r));
+
+            if ram_record.is_any_recording() {
+                let [in_main_l, in_main_r, in_side_l, in_side_r] = if engine_clock {
+                    input_queue.pop()
+                } else {
+                    input
+                };
+                let tap = ResampleTap {
+                    mix: (main_l, main_r),
+                    channels: contributions,
+                };
+                ram_record.record_all(in_main_l, in_main_r, in_side_l, in_side_r, &tap);
+            }
+
+            let mut frame = [0.0; MULTI_OUT_CHANNELS];
+            frame[0] = main_l;
+            frame[1] = main_r;
+            if aux_outputs {
+                for (bus, (bus_l, bus_r)) in mixer.mix_buses(&channels).into_iter().enumerate() {
+                    frame[2 + bus * 2] = bus_l;
+                    frame[3 + bus * 2] = bus_r;
+                }
+            }
+            frame
+        };
+
+        if !aux_outputs {
+            let main = if engine_clock {
+                let [l, r] = self.play_resampler.next_frame(|| {
+                    let frame = render();
+                    [frame[0], frame[1]]
+                });
+                (l, r)
+            } else {
+                let frame = render();
+                (frame[0], frame[1])
+            };
+            return (main, [(0.0, 0.0); AUX_BUSES]);
+        }
+
+        let frame = if engine_clock {
+            self.multi_out_resampler.next_frame(render)
+        } else {
+            render()
+        };
+        let buses = std::array::from_fn(|bus| (frame[2 + bus * 2], frame[3 + bus * 2]));
+        ((frame[0], frame[1]), buses)
+    }
+
+    /// Samples the engine clock's resamplers delay the output and the
+    /// recorded input by; zero when the machines run at the host rate
+    fn latency(&self) -> u32 {
+        if !self.engine_clock {
+            return 0;
+        }
+        let output = if self.aux_outputs {
+            self.multi_out_resampler.latency()
+        } else {
+            self.play_resampler.latency()
+        };
+        (output + self.record_resampler.latency()) as u32
+    }
+
+    /// Runs the RAM machines at the fixed engine clock or at the host rate
+    fn set_engine_clock(&mut self, enabled: bool) {
+        self.engine_clock = enabled;
+        let machine_rate = if enabled {
+            ENGINE_SAMPLE_RATE
+        } else {
+            self.sample_rate
+        };
+        self.ram_record.set_sample_rate(machine_rate);
+        self.ram_play.set_sample_rate(machine_rate);
+        for filter in &mut self.filters {
+            filter.set_sample_rate(machine_rate);
+        }
+        self.play_resampler.reset();
+        self.multi_out_resampler.reset();
+        self.record_resampler.reset();
     }
 }
 
@@ -158,3 +568,146 @@ impl Vst3Plugin for Ultrawave {
 
 nih_export_clap!(Ultrawave);
 nih_export_vst3!(Ultrawave);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note_on(note: u8) -> PluginNoteEvent<Ultrawave> {
+        NoteEvent::NoteOn {
+            timing: 0,
+            voice_id: None,
+            channel: 0,
+            note,
+            velocity: 1.0,
+        }
+    }
+
+    fn note_off(note: u8) -> PluginNoteEvent<Ultrawave> {
+        NoteEvent::NoteOff {
+            timing: 0,
+            voice_id: None,
+            channel: 0,
+            note,
+            velocity: 0.0,
+        }
+    }
+
+    #[test]
+    fn test_old_volume_and_pan_move_to_channel_one() {
+        use nih_plug::wrapper::state::ParamValue;
+
+        let mut state = PluginState {
+            version: String::new(),
+            params: [
+                ("vol".to_string(), ParamValue::I32(80)),
+                ("pan".to_string(), ParamValue::I32(20)),
+                ("pan_1".to_string(), ParamValue::I32(30)),
+            ]
+            .into_iter()
+            .collect(),
+            fields: Default::default(),
+        };
+        Ultrawave::filter_state(&mut state);
+
+        assert!(matches!(
+            state.params.get("vol_1"),
+            Some(ParamValue::I32(80))
+        ));
+        // Values saved under the new IDs win
+        assert!(matches!(
+            state.params.get("pan_1"),
+            Some(ParamValue::I32(30))
+        ));
+        assert!(!state.params.contains_key("vol"));
+        assert!(!state.params.contains_key("pan"));
+    }
+
+    #[test]
+    fn test_resampling_records_the_filtered_mix() {
+        let mut plugin = Ultrawave::default();
+        plugin.prepare(44100.0, false);
+        plugin.set_engine_clock(false);
+        // A high-pass that removes all of a constant buffer
+        for filter in &mut plugin.filters {
+            filter.set_params(5000.0, 0.0, dsp::filter::FilterMode::HighPass);
+        }
+        plugin.ram_play.load_buffer(vec![1024; 44100], 0);
+        let play_params = RamPlayMachineParams {
+            hold: 127,
+            ..Default::default()
+        };
+        plugin.ram_play.trigger(&play_params, 0);
+
+        let record_params = RamRecordMachineParams {
+            src: 1,
+            mlev: 127,
+            rec_len: 127,
+            rec_rate: 127,
+            ..Default::default()
+        };
+        plugin.ram_record.start_recording(&record_params, true, 1);
+        let mut main = (0.0, 0.0);
+        for _ in 0..4000 {
+            main = plugin.render_frame([0.0; 4]).0;
+        }
+        plugin.ram_record.stop_recording(1);
+
+        assert!(plugin.ram_play.is_playing(0));
+        assert!(main.0.abs() < 0.001 && main.1.abs() < 0.001);
+        let recorded = plugin.ram_record.get_buffer(1);
+        assert_eq!(recorded.len(), 4000);
+        assert!(recorded[1000..].iter().all(|sample| sample.abs() <= 2));
+    }
+
+    #[test]
+    fn test_latency_follows_engine_clock() {
+        let mut plugin = Ultrawave::default();
+        plugin.prepare(48000.0, false);
+        plugin.set_engine_clock(false);
+        assert_eq!(plugin.latency(), 0);
+
+        plugin.set_engine_clock(true);
+        let expected = plugin.play_resampler.latency() + plugin.record_resampler.latency();
+        assert!(expected > 0);
+        assert_eq!(plugin.latency(), expected as u32);
+
+        // Nothing to resample at the engine's own rate
+        plugin.prepare(ENGINE_SAMPLE_RATE, false);
+        plugin.set_engine_clock(true);
+        assert_eq!(plugin.latency(), 0);
+    }
+
+    #[test]
+    fn test_process_path_does_not_allocate() {
+        let mut plugin = Ultrawave::default();
+        // A host rate that differs from the engine clock, with aux outputs
+        plugin.prepare(48000.0, true);
+        let record_note = plugin.params.record_note.value() as u8;
+
+        let heap_calls = alloc_check::heap_calls_in(|| {
+            for engine_clock in [false, true] {
+                plugin.set_engine_clock(engine_clock);
+                plugin.handle_event(note_on(record_note));
+                for _ in 0..2000 {
+                    if engine_clock {
+                        let input_queue = &mut plugin.input_queue;
+                        plugin
+                            .record_resampler
+                            .push_frame([0.5; 4], |frame| input_queue.push(frame));
+                    }
+                    plugin.render_frame([0.5; 4]);
+                }
+                plugin.handle_event(note_off(record_note));
+
+                plugin.handle_event(note_on(60));
+                for _ in 0..2000 {
+                    plugin.render_frame([0.0; 4]);
+                }
+                plugin.handle_event(note_off(60));
+            }
+        });
+        assert_eq!(heap_calls, 0);
+        assert!(plugin.ram_record.buffer_len(0) > 0);
+    }
+}
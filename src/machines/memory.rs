@@ -0,0 +1,294 @@
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+use crate::dsp::sample_engine::BufferFormat;
+
+/// Sample memory of the Machinedrum UW, shared by all RAM machines
+pub const HARDWARE_MEMORY_BYTES: usize = 2_621_440;
+
+/// Budget used until one is set
+pub const DEFAULT_MEMORY_BYTES: usize = 32 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemoryPolicy {
+    /// Recordings that don't fit are shortened to the free memory
+    Truncate,
+    /// Recordings that don't fit don't start
+    Refuse,
+}
+
+impl MemoryPolicy {
+    /// Convert 0-1 parameter to memory policy
+    /// 0: Truncate, 1: Refuse
+    pub fn from_param(value: i32) -> Self {
+        match value {
+            1 => MemoryPolicy::Refuse,
+            _ => MemoryPolicy::Truncate,
+        }
+    }
+}
+
+/// Memory budget shared by `CHANNELS` sample buffers. Tracks bytes per
+/// channel at each buffer format's bit depth, the way the hardware packs
+/// 12-bit samples.
+///
+/// This only does the accounting. `RamRecord` carves its buffers out of a
+/// pool sized by the budget; registry slots keep their own storage.
+#[derive(Debug, Clone)]
+pub struct SampleMemory<const CHANNELS: usize> {
+    budget: usize,
+    used: [usize; CHANNELS],
+    policy: MemoryPolicy,
+}
+
+impl<const CHANNELS: usize> SampleMemory<CHANNELS> {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            used: [0; CHANNELS],
+            policy: MemoryPolicy::Truncate,
+        }
+    }
+
+    /// Bytes taken by `samples` individual samples in `format`
+    pub fn bytes_for(samples: usize, format: BufferFormat) -> usize {
+        (samples * format.bits() as usize).div_ceil(8)
+    }
+
+    /// Changes the budget. Existing buffers are kept even when they no
+    /// longer fit; free memory reads as zero until enough is released.
+    pub fn set_budget(&mut self, budget: usize) {
+        self.budget = budget;
+    }
+
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    pub fn set_policy(&mut self, policy: MemoryPolicy) {
+        self.policy = policy;
+    }
+
+    pub fn policy(&self) -> MemoryPolicy {
+        self.policy
+    }
+
+    /// Bytes held by one channel
+    pub fn used(&self, channel: usize) -> usize {
+        self.used.get(channel).copied().unwrap_or(0)
+    }
+
+    pub fn total_used(&self) -> usize {
+        self.used.iter().sum()
+    }
+
+    /// Bytes not held by any channel
+    pub fn free(&self) -> usize {
+        self.budget.saturating_sub(self.total_used())
+    }
+
+    /// Samples a channel could hold in `format`, counting the memory it
+    /// already has
+    pub fn available_samples(&self, channel: usize, format: BufferFormat) -> usize {
+        let available = self.free() + self.used(channel);
+        available * 8 / format.bits() as usize
+    }
+
+    /// Frames of a recording of `frames` the channel is allowed under the
+    /// current policy. Doesn't take the memory; see `set_used`.
+    pub fn grant(&self, channel: usize, frames: usize, format: BufferFormat) -> usize {
+        let fit = self.available_samples(channel, format) / format.channels();
+        match self.policy {
+            MemoryPolicy::Truncate => frames.min(fit),
+            MemoryPolicy::Refuse if fit < frames => 0,
+            MemoryPolicy::Refuse => frames,
+        }
+    }
+
+    /// Records that a channel now holds `samples` samples in `format`
+    pub fn set_used(&mut self, channel: usize, samples: usize, format: BufferFormat) {
+        if let Some(used) = self.used.get_mut(channel) {
+            *used = Self::bytes_for(samples, format);
+        }
+    }
+
+    pub fn release(&mut self, channel: usize) {
+        if let Some(used) = self.used.get_mut(channel) {
+            *used = 0;
+        }
+    }
+
+    pub fn release_all(&mut self) {
+        self.used = [0; CHANNELS];
+    }
+}
+
+impl<const CHANNELS: usize> Default for SampleMemory<CHANNELS> {
+    fn default() -> Self {
+        Self::new(DEFAULT_MEMORY_BYTES)
+    }
+}
+
+/// `SampleMemory` that several threads update without locking. Each
+/// channel should have one writer at a time; writers on different channels
+/// can overcommit the budget by one grant when they race.
+#[derive(Debug)]
+pub struct AtomicSampleMemory<const CHANNELS: usize> {
+    budget: AtomicUsize,
+    used: [AtomicUsize; CHANNELS],
+    policy: AtomicU8,
+}
+
+impl<const CHANNELS: usize> AtomicSampleMemory<CHANNELS> {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            budget: AtomicUsize::new(budget),
+            used: std::array::from_fn(|_| AtomicUsize::new(0)),
+            policy: AtomicU8::new(MemoryPolicy::Truncate as u8),
+        }
+    }
+
+    /// See `SampleMemory::set_budget`
+    pub fn set_budget(&self, budget: usize) {
+        self.budget.store(budget, Ordering::Relaxed);
+    }
+
+    pub fn budget(&self) -> usize {
+        self.budget.load(Ordering::Relaxed)
+    }
+
+    pub fn set_policy(&self, policy: MemoryPolicy) {
+        self.policy.store(policy as u8, Ordering::Relaxed);
+    }
+
+    pub fn policy(&self) -> MemoryPolicy {
+        MemoryPolicy::from_param(self.policy.load(Ordering::Relaxed) as i32)
+    }
+
+    /// Bytes held by one channel
+    pub fn used(&self, channel: usize) -> usize {
+        self.used
+            .get(channel)
+            .map_or(0, |used| used.load(Ordering::Acquire))
+    }
+
+    pub fn total_used(&self) -> usize {
+        self.used
+            .iter()
+            .map(|used| used.load(Ordering::Acquire))
+            .sum()
+    }
+
+    /// Bytes not held by any channel
+    pub fn free(&self) -> usize {
+        self.budget().saturating_sub(self.total_used())
+    }
+
+    /// See `SampleMemory::available_samples`
+    pub fn available_samples(&self, channel: usize, format: BufferFormat) -> usize {
+        self.snapshot().available_samples(channel, format)
+    }
+
+    /// See `SampleMemory::grant`
+    pub fn grant(&self, channel: usize, frames: usize, format: BufferFormat) -> usize {
+        self.snapshot().grant(channel, frames, format)
+    }
+
+    /// Records that a channel now holds `samples` samples in `format`
+    pub fn set_used(&self, channel: usize, samples: usize, format: BufferFormat) {
+        if let Some(used) = self.used.get(channel) {
+            used.store(
+                SampleMemory::<CHANNELS>::bytes_for(samples, format),
+                Ordering::Release,
+            );
+        }
+    }
+
+    pub fn release(&self, channel: usize) {
+        if let Some(used) = self.used.get(channel) {
+            used.store(0, Ordering::Release);
+        }
+    }
+
+    /// Current accounting as a plain `SampleMemory`, which doesn't allocate
+    fn snapshot(&self) -> SampleMemory<CHANNELS> {
+        SampleMemory {
+            budget: self.budget(),
+            used: std::array::from_fn(|channel| self.used(channel)),
+            policy: self.policy(),
+        }
+    }
+}
+
+impl<const CHANNELS: usize> Default for AtomicSampleMemory<CHANNELS> {
+    fn default() -> Self {
+        Self::new(DEFAULT_MEMORY_BYTES)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bytes_follow_bit_depth() {
+        assert_eq!(SampleMemory::<1>::bytes_for(2, BufferFormat::Mono12), 3);
+        assert_eq!(SampleMemory::<1>::bytes_for(3, BufferFormat::Mono12), 5);
+        assert_eq!(SampleMemory::<1>::bytes_for(2, BufferFormat::Stereo16), 4);
+    }
+
+    #[test]
+    fn test_hardware_budget_holds_about_forty_seconds() {
+        let memory = SampleMemory::<8>::new(HARDWARE_MEMORY_BYTES);
+        let frames = memory.grant(0, usize::MAX, BufferFormat::Mono12);
+        let seconds = frames as f32 / 44100.0;
+        assert!((39.0..40.0).contains(&seconds));
+    }
+
+    #[test]
+    fn test_channels_share_the_budget() {
+        let mut memory = SampleMemory::<4>::new(3000);
+        memory.set_used(0, 1000, BufferFormat::Mono12);
+        assert_eq!(memory.used(0), 1500);
+        assert_eq!(memory.free(), 1500);
+        assert_eq!(memory.grant(1, 2000, BufferFormat::Mono12), 1000);
+        // A channel can reuse what it already holds
+        assert_eq!(memory.grant(0, 2000, BufferFormat::Mono12), 2000);
+
+        memory.release(0);
+        assert_eq!(memory.total_used(), 0);
+    }
+
+    #[test]
+    fn test_refuse_policy() {
+        let mut memory = SampleMemory::<2>::new(100);
+        memory.set_policy(MemoryPolicy::Refuse);
+        assert_eq!(memory.grant(0, 50, BufferFormat::Stereo16), 0);
+        assert_eq!(memory.grant(0, 25, BufferFormat::Stereo16), 25);
+    }
+
+    #[test]
+    fn test_shrinking_budget_keeps_buffers() {
+        let mut memory = SampleMemory::<2>::new(1000);
+        memory.set_used(0, 400, BufferFormat::Stereo16);
+        memory.set_budget(500);
+        assert_eq!(memory.used(0), 800);
+        assert_eq!(memory.free(), 0);
+        assert_eq!(memory.grant(1, 10, BufferFormat::Mono12), 0);
+    }
+
+    #[test]
+    fn test_atomic_memory_matches_locked_accounting() {
+        let memory = AtomicSampleMemory::<4>::new(3000);
+        memory.set_used(0, 1000, BufferFormat::Mono12);
+        assert_eq!(memory.used(0), 1500);
+        assert_eq!(memory.free(), 1500);
+        assert_eq!(memory.grant(1, 2000, BufferFormat::Mono12), 1000);
+
+        memory.set_policy(MemoryPolicy::Refuse);
+        assert_eq!(memory.policy(), MemoryPolicy::Refuse);
+        assert_eq!(memory.grant(1, 2000, BufferFormat::Mono12), 0);
+
+        memory.release(0);
+        assert_eq!(memory.total_used(), 0);
+    }
+}
@@ -1,3 +1,5 @@
+pub mod memory;
 pub mod ram_play;
 pub mod ram_record;
 pub mod shared;
+pub mod shared_memory;
@@ -1,4 +1,78 @@
-use crate::dsp::sample_engine::{param_to_normalized, pitch_to_ratio, SampleEngine};
+use std::sync::Arc;
+
+use crate::dsp::envelope::{DecayCurve, Envelope, EnvelopeMode};
+use crate::dsp::sample_buffer::SampleBuffer;
+use crate::dsp::sample_engine::{
+    param_to_normalized, pitch_to_ratio, semitones_to_ratio, BufferFormat, DitherMode,
+    InterpolationMode, LoopMode, PlaybackDirection, SampleEngine,
+};
+
+/// Voices per channel in poly mode
+pub const MAX_VOICES: usize = 16;
+
+// One bit per voice in `Channel::busy`
+const _: () = assert!(MAX_VOICES <= u32::BITS as usize);
+
+/// Tails a voice can fade at once: enough for retrigs at the shortest
+/// nonzero RTIM under the longest DCLK
+const MAX_TAILS: usize = 8;
+
+/// `RamPlay::share_buffer` calls a channel may take between
+/// `RamPlay::release_retired` calls: the plugin shares once when built and
+/// once when prepared, players at most once per block
+const MAX_SHARES: usize = 2;
+
+/// Buffers a voice can let go of between `RamPlay::release_retired` calls.
+/// Each share moves the voice off one buffer, and each tail off the one it
+/// captured.
+const MAX_RETIRED: usize = MAX_SHARES * (MAX_TAILS + 1);
+
+/// Keeps a buffer that was let go of on the audio thread, so the last
+/// reference isn't dropped there. `retired` has room for everything let go
+/// of between releases, so this doesn't allocate either.
+fn retire(retired: &mut Vec<Arc<SampleBuffer>>, buffer: Arc<SampleBuffer>) {
+    debug_assert!(
+        retired.len() < retired.capacity(),
+        "more buffers retired than MAX_SHARES allows"
+    );
+    retired.push(buffer);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VoiceMode {
+    /// One voice; a new note crossfades over the sounding one
+    Mono,
+    /// New notes take a free voice from the pool
+    Poly,
+}
+
+impl VoiceMode {
+    /// Convert 0-1 parameter to voice mode
+    /// 0: Mono, 1: Poly
+    pub fn from_param(value: i32) -> Self {
+        match value {
+            1 => VoiceMode::Poly,
+            _ => VoiceMode::Mono,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StealMode {
+    Oldest,
+    Quietest,
+}
+
+impl StealMode {
+    /// Convert 0-1 parameter to steal mode
+    /// 0: Oldest, 1: Quietest
+    pub fn from_param(value: i32) -> Self {
+        match value {
+            1 => StealMode::Quietest,
+            _ => StealMode::Oldest,
+        }
+    }
+}
 
 #[derive(Clone, Copy)]
 pub struct RamPlayParams {
@@ -11,6 +85,33 @@ pub struct RamPlayParams {
     pub rtim: i32,
     pub srr: i32,
     pub vol: i32,
+    pub intp: i32,
+    pub loop_mode: i32,
+    pub lstr: i32,
+    pub plbk: i32,
+    pub slices: i32,
+    pub slice: i32,
+    pub dith: i32,
+    /// Semitones from the root note (key tracking)
+    pub key: i32,
+    /// Fine tune in cents (-100 to 100)
+    pub tune: i32,
+    /// Pitch-bend range in semitones
+    pub bend: i32,
+    /// Declick fade length in milliseconds
+    pub dclk: i32,
+    pub atk: i32,
+    pub rel: i32,
+    /// Decay and release curve (0: linear, 1: exponential)
+    pub dcrv: i32,
+    /// Envelope mode (0: one-shot, 1: gate)
+    pub envm: i32,
+    /// Voice mode (0: mono, 1: poly)
+    pub poly: i32,
+    /// Poly voice pool size (1 to `MAX_VOICES`)
+    pub voices: i32,
+    /// Voice stealing (0: oldest, 1: quietest)
+    pub steal: i32,
 }
 
 impl Default for RamPlayParams {
@@ -25,172 +126,639 @@ impl Default for RamPlayParams {
             rtim: 0,
             srr: 0,
             vol: 100,
+            intp: 1,
+            loop_mode: 0,
+            lstr: 0,
+            plbk: 0,
+            slices: 1,
+            slice: 0,
+            dith: 0,
+            key: 0,
+            tune: 0,
+            bend: 2,
+            dclk: 2,
+            atk: 0,
+            rel: 0,
+            dcrv: 0,
+            envm: 1,
+            poly: 0,
+            voices: 8,
+            steal: 0,
         }
     }
 }
 
-pub struct RamPlay {
-    engines: [SampleEngine; 8],
-    envelopes: [f32; 8],
-    is_playing: [bool; 8],
-    hold_time_samples: [usize; 8],
-    hold_counters: [usize; 8],
-    decay_rates: [f32; 8],
+/// Maps a MIDI note to a slice index, counting up from `base_note`.
+/// With a single slice every note plays the whole buffer; otherwise notes
+/// outside `base_note..base_note + slices` map to nothing.
+pub fn slice_for_note(note: u8, base_note: i32, slices: i32) -> Option<i32> {
+    if slices <= 1 {
+        return Some(0);
+    }
+    let slice = note as i32 - base_note;
+    if (0..slices).contains(&slice) {
+        Some(slice)
+    } else {
+        None
+    }
+}
+
+/// Read head of a voice that was cut by a retrigger, fading out under the
+/// new start.
+struct VoiceTail {
+    /// What the voice was playing, which a new start may have moved off
+    buffer: Arc<SampleBuffer>,
+    position: f64,
+    /// Frames advanced per sample, signed by direction
+    step: f64,
+    gain: f32,
+    fade_step: f32,
+}
+
+/// One playback voice: read head, envelope and retrig state over a shared
+/// channel buffer.
+struct Voice {
+    engine: SampleEngine,
+    envelope: Envelope,
+    params: RamPlayParams,
+    /// MIDI note that started the voice, for note-off matching
+    note: Option<u8>,
+    /// Trigger order, for oldest-voice stealing
+    started: u64,
     sample_rate: f32,
-    retrig_counters: [usize; 8],
-    retrig_intervals: [usize; 8],
-    retrigs_remaining: [usize; 8],
-    current_params: [RamPlayParams; 8],
+    is_playing: bool,
+    gate_held: bool,
+    retrig_counter: usize,
+    retrig_interval: usize,
+    retrigs_remaining: usize,
+    /// Declick fade gain, ramping by `declick_step` each sample
+    declick_gain: f32,
+    declick_step: f32,
+    /// Gain applied to the last rendered sample
+    output_gain: f32,
+    /// Playback ratio of the last rendered sample
+    pitch_ratio: f64,
+    /// Earlier starts still fading out
+    tails: [VoiceTail; MAX_TAILS],
+    /// Tails with gain left, so voices without any skip the tail loop
+    sounding_tails: usize,
+    /// Buffers the voice moved off, waiting to be freed off the audio thread
+    retired: Vec<Arc<SampleBuffer>>,
 }
 
-impl RamPlay {
-    pub fn new(sample_rate: f32) -> Self {
+impl Voice {
+    fn new(sample_rate: f32) -> Self {
+        let engine = SampleEngine::new(sample_rate);
         Self {
-            engines: [
-                SampleEngine::new(sample_rate),
-                SampleEngine::new(sample_rate),
-                SampleEngine::new(sample_rate),
-                SampleEngine::new(sample_rate),
-                SampleEngine::new(sample_rate),
-                SampleEngine::new(sample_rate),
-                SampleEngine::new(sample_rate),
-                SampleEngine::new(sample_rate),
-            ],
-            envelopes: [0.0; 8],
-            is_playing: [false; 8],
-            hold_time_samples: [0; 8],
-            hold_counters: [0; 8],
-            decay_rates: [0.0; 8],
+            // Idle tails share the engine's empty buffer
+            tails: std::array::from_fn(|_| VoiceTail {
+                buffer: engine.buffer().clone(),
+                position: 0.0,
+                step: 0.0,
+                gain: 0.0,
+                fade_step: 0.0,
+            }),
+            sounding_tails: 0,
+            engine,
+            envelope: Envelope::new(sample_rate),
+            params: RamPlayParams::default(),
+            note: None,
+            started: 0,
             sample_rate,
-            retrig_counters: [0; 8],
-            retrig_intervals: [0; 8],
-            retrigs_remaining: [0; 8],
-            current_params: [RamPlayParams::default(); 8],
+            is_playing: false,
+            gate_held: false,
+            retrig_counter: 0,
+            retrig_interval: 0,
+            retrigs_remaining: 0,
+            declick_gain: 0.0,
+            declick_step: 0.0,
+            output_gain: 0.0,
+            pitch_ratio: 1.0,
+            retired: Vec::with_capacity(MAX_RETIRED),
         }
     }
 
-    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+    fn set_sample_rate(&mut self, sample_rate: f32) {
         self.sample_rate = sample_rate;
-        for engine in &mut self.engines {
-            engine.set_sample_rate(sample_rate);
-        }
+        self.engine.set_sample_rate(sample_rate);
+        self.envelope.set_sample_rate(sample_rate);
     }
 
-    pub fn load_buffer(&mut self, samples: Vec<i16>, channel: usize) {
-        let channel = channel.min(7);
-        self.engines[channel].load_buffer(samples);
+    /// Whether the voice is sounding or waiting on a retrig.
+    fn is_active(&self) -> bool {
+        self.is_playing || self.retrigs_remaining > 0
     }
 
-    pub fn load_all_buffers(&mut self, buffers: &[Vec<i16>; 8]) {
-        for (i, buffer) in buffers.iter().enumerate() {
-            self.engines[i].load_buffer(buffer.clone());
-        }
+    /// Whether processing the voice can produce output: it is active or
+    /// still fading out earlier starts.
+    fn is_sounding(&self) -> bool {
+        self.is_active() || self.sounding_tails > 0
     }
 
-    pub fn trigger(&mut self, params: &RamPlayParams, channel: usize) {
-        let channel = channel.min(7);
-        self.current_params[channel] = *params;
+    /// Starts playing `buffer`. A sounding voice fades out on the buffer it
+    /// was playing.
+    fn start(
+        &mut self,
+        params: &RamPlayParams,
+        note: Option<u8>,
+        started: u64,
+        buffer: &Arc<SampleBuffer>,
+    ) {
+        self.params = *params;
+        self.note = note;
+        self.started = started;
+        self.gate_held = true;
 
-        self.trigger_internal(channel);
+        self.capture_tail();
+        if self.engine.shares_buffer(buffer) {
+            // The recorder may have written more since the last note
+            self.engine.refresh_slices();
+        } else {
+            self.set_buffer(buffer.clone());
+        }
+        self.engine.set_slice_count(params.slices.max(1) as usize);
+
+        self.trigger_internal();
 
         if params.rtrg > 0 {
-            self.retrigs_remaining[channel] = params.rtrg as usize;
+            self.retrigs_remaining = params.rtrg as usize;
             let rtim_ms = param_to_normalized(params.rtim) * 500.0;
-            self.retrig_intervals[channel] = (rtim_ms * self.sample_rate / 1000.0) as usize;
-            self.retrig_counters[channel] = 0;
+            self.retrig_interval = (rtim_ms * self.sample_rate / 1000.0) as usize;
+            self.retrig_counter = 0;
         } else {
-            self.retrigs_remaining[channel] = 0;
+            self.retrigs_remaining = 0;
         }
     }
 
-    fn trigger_internal(&mut self, channel: usize) {
-        let buffer_len = self.engines[channel].buffer_len() as f64;
-        let start_norm = param_to_normalized(self.current_params[channel].strt);
-        let start_pos = (start_norm as f64) * buffer_len;
-        self.engines[channel].trigger(start_pos);
+    /// Plays `buffer` from the next start on
+    fn set_buffer(&mut self, buffer: Arc<SampleBuffer>) {
+        let previous = self.engine.set_shared_buffer(buffer);
+        retire(&mut self.retired, previous);
+    }
 
-        self.envelopes[channel] = 1.0;
-        self.is_playing[channel] = true;
+    /// Moves silent tails back onto the engine's buffer, retiring the ones
+    /// they held
+    fn retire_idle_tails(&mut self) {
+        for tail in &mut self.tails {
+            if tail.gain <= 0.0 && !Arc::ptr_eq(&tail.buffer, self.engine.buffer()) {
+                let previous = std::mem::replace(&mut tail.buffer, self.engine.buffer().clone());
+                retire(&mut self.retired, previous);
+            }
+        }
+    }
 
-        let hold_seconds = param_to_normalized(self.current_params[channel].hold) * 2.0;
-        self.hold_time_samples[channel] = (hold_seconds * self.sample_rate) as usize;
-        self.hold_counters[channel] = 0;
+    /// Keeps a sounding voice playing as a tail that crossfades out under
+    /// the next start. Tails from earlier starts keep fading; when all are
+    /// in use the quietest gives way.
+    fn capture_tail(&mut self) {
+        let fade_samples = self.fade_samples();
+        if !self.is_playing || self.output_gain <= 0.0 || fade_samples < 1.0 {
+            return;
+        }
+        let Some(tail) = self
+            .tails
+            .iter_mut()
+            .min_by(|a, b| a.gain.total_cmp(&b.gain))
+        else {
+            return;
+        };
+        if tail.gain <= 0.0 {
+            self.sounding_tails += 1;
+        }
+        if !Arc::ptr_eq(&tail.buffer, self.engine.buffer()) {
+            let previous = std::mem::replace(&mut tail.buffer, self.engine.buffer().clone());
+            retire(&mut self.retired, previous);
+        }
+        tail.position = self.engine.position();
+        tail.step = self.pitch_ratio * self.engine.direction();
+        tail.gain = self.output_gain;
+        tail.fade_step = self.output_gain / fade_samples;
+    }
 
-        let decay_seconds = param_to_normalized(self.current_params[channel].dec) * 4.0;
-        self.decay_rates[channel] = if decay_seconds > 0.0 {
-            1.0 / (decay_seconds * self.sample_rate)
+    fn trigger_internal(&mut self) {
+        let fade_samples = self.fade_samples();
+        if fade_samples >= 1.0 {
+            self.declick_gain = 0.0;
+            self.declick_step = 1.0 / fade_samples;
         } else {
-            1.0
+            self.declick_gain = 1.0;
+            self.declick_step = 0.0;
+        }
+
+        let (start_pos, _, end_pos) = self.play_region();
+        let direction = PlaybackDirection::from_param(self.params.plbk);
+        let trigger_pos = match direction {
+            PlaybackDirection::Forward => start_pos,
+            // Reverse playback runs from the last sample before END back to STRT
+            PlaybackDirection::Reverse => (end_pos - 1.0).max(0.0),
         };
+        self.engine.trigger_with_direction(trigger_pos, direction);
+
+        self.envelope.trigger(
+            self.params.atk,
+            self.params.hold,
+            self.params.dec,
+            DecayCurve::from_param(self.params.dcrv),
+        );
+        self.is_playing = true;
     }
 
-    pub fn stop(&mut self, channel: usize) {
-        let channel = channel.min(7);
-        self.is_playing[channel] = false;
-        self.envelopes[channel] = 0.0;
-        self.retrigs_remaining[channel] = 0;
+    /// STRT, loop start and END in frames. STRT/END/LSTR are relative to the
+    /// selected slice, which is the whole buffer unless slicing is enabled.
+    fn play_region(&self) -> (f64, f64, f64) {
+        let params = &self.params;
+        let (slice_start, slice_end) = self.engine.slice_bounds(params.slice.max(0) as usize);
+        let slice_len = slice_end - slice_start;
+
+        let start_pos = slice_start + param_to_normalized(params.strt) as f64 * slice_len;
+        let end_pos = slice_start + param_to_normalized(params.end) as f64 * slice_len;
+        let loop_start = (slice_start + param_to_normalized(params.lstr) as f64 * slice_len)
+            .max(start_pos)
+            .min(end_pos);
+        (start_pos, loop_start, end_pos)
     }
 
-    pub fn stop_all(&mut self) {
-        self.is_playing = [false; 8];
-        self.envelopes = [0.0; 8];
-        self.retrigs_remaining = [0; 8];
+    fn stop(&mut self) {
+        self.gate_held = false;
+        self.retrigs_remaining = 0;
+        self.fade_out();
     }
 
-    pub fn process(&mut self, channel: usize) -> f32 {
-        let channel = channel.min(7);
+    /// Note-off. In gate mode the envelope moves to its release stage. In
+    /// one-shot mode the note plays on; looping voices just leave sustain.
+    fn release(&mut self) {
+        self.gate_held = false;
+        if EnvelopeMode::from_param(self.params.envm) == EnvelopeMode::OneShot {
+            return;
+        }
+
+        self.retrigs_remaining = 0;
+        self.envelope.release(self.params.rel);
+        if self.envelope.is_finished() {
+            self.fade_out();
+        }
+    }
 
-        if self.retrigs_remaining[channel] > 0 {
-            self.retrig_counters[channel] += 1;
-            if self.retrig_counters[channel] >= self.retrig_intervals[channel] {
-                self.retrig_counters[channel] = 0;
-                self.retrigs_remaining[channel] -= 1;
-                self.trigger_internal(channel);
+    /// Starts the declick fade-out, or cuts the voice if nothing is audible
+    /// yet or declick is off.
+    fn fade_out(&mut self) {
+        if self.declick_step < 0.0 {
+            return;
+        }
+        let fade_samples = self.fade_samples();
+        if self.is_playing && self.output_gain > 0.0 && fade_samples >= 1.0 {
+            self.declick_step = -1.0 / fade_samples;
+        } else {
+            self.end();
+        }
+    }
+
+    fn end(&mut self) {
+        self.is_playing = false;
+        self.output_gain = 0.0;
+        self.declick_step = 0.0;
+    }
+
+    fn fade_samples(&self) -> f32 {
+        self.params.dclk.max(0) as f32 * self.sample_rate / 1000.0
+    }
+
+    fn process(&mut self, pitch_bend: f32) -> (f32, f32) {
+        if self.retrigs_remaining > 0 {
+            self.retrig_counter += 1;
+            if self.retrig_counter >= self.retrig_interval {
+                self.retrig_counter = 0;
+                self.retrigs_remaining -= 1;
+                self.capture_tail();
+                self.trigger_internal();
             }
         }
 
-        if !self.is_playing[channel] {
-            return 0.0;
+        let (tail_l, tail_r) = self.process_tails();
+        let (left, right) = self.process_voice(pitch_bend);
+        (left + tail_l, right + tail_r)
+    }
+
+    fn process_tails(&mut self) -> (f32, f32) {
+        if self.sounding_tails == 0 {
+            return (0.0, 0.0);
+        }
+        let mode = InterpolationMode::from_param(self.params.intp);
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for tail in &mut self.tails {
+            if tail.gain <= 0.0 {
+                continue;
+            }
+            let Some((l, r)) =
+                SampleEngine::frame_in(&tail.buffer, tail.position, tail.step.abs(), mode)
+            else {
+                tail.gain = 0.0;
+                self.sounding_tails -= 1;
+                continue;
+            };
+            left += l * tail.gain;
+            right += r * tail.gain;
+            tail.position += tail.step;
+            tail.gain = (tail.gain - tail.fade_step).max(0.0);
+            if tail.gain <= 0.0 {
+                self.sounding_tails -= 1;
+            }
+        }
+        (left, right)
+    }
+
+    fn process_voice(&mut self, pitch_bend: f32) -> (f32, f32) {
+        if !self.is_playing {
+            return (0.0, 0.0);
         }
 
-        let buffer_len = self.engines[channel].buffer_len() as f64;
-        let end_norm = param_to_normalized(self.current_params[channel].end);
-        let end_pos = (end_norm as f64) * buffer_len;
+        let (start_pos, loop_start, end_pos) = self.play_region();
+        let loop_mode = LoopMode::from_param(self.params.loop_mode);
+        let is_looping = loop_mode != LoopMode::Off && loop_start < end_pos;
 
-        if self.engines[channel].is_finished(end_pos) {
-            self.is_playing[channel] = false;
-            return 0.0;
+        if !is_looping && self.engine.is_finished(start_pos, end_pos) {
+            self.end();
+            return (0.0, 0.0);
         }
 
-        let pitch_ratio = pitch_to_ratio(self.current_params[channel].pitch);
-        let sample = self.engines[channel]
-            .read_sample_with_processing(pitch_ratio, self.current_params[channel].srr);
+        self.declick_gain = (self.declick_gain + self.declick_step).clamp(0.0, 1.0);
+        if self.declick_step < 0.0 && self.declick_gain <= 0.0 {
+            self.end();
+            return (0.0, 0.0);
+        }
+
+        let params = &self.params;
+        let offset = params.key as f64
+            + params.tune as f64 / 100.0
+            + (pitch_bend * params.bend as f32) as f64;
+        let pitch_ratio = pitch_to_ratio(params.pitch) * semitones_to_ratio(offset);
+        self.pitch_ratio = pitch_ratio;
 
-        if self.hold_counters[channel] < self.hold_time_samples[channel] {
-            self.hold_counters[channel] += 1;
+        // One-shots fade out as they approach END (or STRT in reverse)
+        let fade_samples = self.fade_samples();
+        let end_gain = if is_looping || fade_samples < 1.0 {
+            1.0
         } else {
-            self.envelopes[channel] =
-                (self.envelopes[channel] - self.decay_rates[channel]).max(0.0);
-            if self.envelopes[channel] <= 0.0 {
-                self.is_playing[channel] = false;
+            let position = self.engine.position();
+            let distance = if self.engine.direction() > 0.0 {
+                end_pos - position
+            } else {
+                position - start_pos + 1.0
+            };
+            ((distance / pitch_ratio) as f32 / fade_samples).clamp(0.0, 1.0)
+        };
+
+        let mode = InterpolationMode::from_param(self.params.intp);
+        let dither = DitherMode::from_param(self.params.dith);
+        let (left, right) =
+            self.engine
+                .read_frame_with_processing(pitch_ratio, self.params.srr, mode, dither);
+
+        if is_looping {
+            self.engine.apply_loop(loop_start, end_pos, loop_mode);
+        }
+
+        // Looping voices stay in hold while the note is held
+        let sustaining = is_looping && self.gate_held;
+        let level = self.envelope.process(sustaining);
+        if self.envelope.is_finished() {
+            if level <= 0.0 {
+                self.end();
+                return (0.0, 0.0);
             }
+            // Instant decay or release; fade rather than cut
+            self.fade_out();
         }
 
-        let vol = param_to_normalized(self.current_params[channel].vol);
-        sample * self.envelopes[channel] * vol
+        let gain = level * self.declick_gain * end_gain * param_to_normalized(self.params.vol);
+        self.output_gain = gain;
+        (left * gain, right * gain)
     }
+}
 
-    pub fn process_mix(&mut self) -> f32 {
-        let mut mix = 0.0;
-        for ch in 0..8 {
-            mix += self.process(ch);
+/// A RAM channel: one recording shared by a pool of voices.
+struct Channel {
+    voices: Vec<Voice>,
+    /// Voices that may be sounding, one bit each, so idle ones aren't
+    /// visited per sample
+    busy: u32,
+    buffer: Arc<SampleBuffer>,
+    /// Buffers the channel moved off, waiting to be freed off the audio thread
+    retired: Vec<Arc<SampleBuffer>>,
+    /// Pitch-bend wheel position, -1.0 to 1.0
+    pitch_bend: f32,
+}
+
+impl Channel {
+    fn new(sample_rate: f32) -> Self {
+        Self {
+            voices: (0..MAX_VOICES).map(|_| Voice::new(sample_rate)).collect(),
+            busy: 0,
+            buffer: Arc::new(SampleBuffer::default()),
+            retired: Vec::with_capacity(MAX_SHARES),
+            pitch_bend: 0.0,
         }
-        mix / 8.0
+    }
+
+    /// Picks the voice for a new note: voice 0 in mono mode, otherwise an
+    /// idle voice within the pool, otherwise one to steal.
+    fn allocate_voice(&self, params: &RamPlayParams) -> usize {
+        if VoiceMode::from_param(params.poly) == VoiceMode::Mono {
+            return 0;
+        }
+        let pool = &self.voices[..params.voices.clamp(1, MAX_VOICES as i32) as usize];
+        if let Some(idle) = pool.iter().position(|voice| !voice.is_active()) {
+            return idle;
+        }
+
+        let candidates = pool.iter().enumerate();
+        let stolen = match StealMode::from_param(params.steal) {
+            StealMode::Oldest => candidates.min_by_key(|(_, voice)| voice.started),
+            StealMode::Quietest => {
+                candidates.min_by(|(_, a), (_, b)| a.output_gain.total_cmp(&b.output_gain))
+            }
+        };
+        stolen.map_or(0, |(index, _)| index)
+    }
+}
+
+pub struct RamPlay {
+    channels: [Channel; 8],
+    sample_rate: f32,
+    /// Counts triggers to order voices by age
+    trigger_count: u64,
+}
+
+impl RamPlay {
+    pub fn new(sample_rate: f32) -> Self {
+        Self {
+            channels: std::array::from_fn(|_| Channel::new(sample_rate)),
+            sample_rate,
+            trigger_count: 0,
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        for channel in &mut self.channels {
+            for voice in &mut channel.voices {
+                voice.set_sample_rate(sample_rate);
+            }
+        }
+    }
+
+    pub fn load_buffer(&mut self, samples: Vec<i16>, channel: usize) {
+        self.load_buffer_with_format(samples, BufferFormat::Mono12, channel);
+    }
+
+    /// Sets the channel's recording from a copy of `samples`. Allocates, so
+    /// keep it off the audio thread.
+    pub fn load_buffer_with_format(
+        &mut self,
+        samples: Vec<i16>,
+        format: BufferFormat,
+        channel: usize,
+    ) {
+        let buffer = Arc::new(SampleBuffer::from_samples(samples, format));
+        self.share_buffer(buffer, channel);
+    }
+
+    /// Plays the channel from a buffer a recorder may still be writing.
+    /// Sounding voices finish on the buffer they started with; new notes
+    /// play the new one. Buffers moved off are kept until
+    /// `release_retired`, so this and later notes never free one; call it
+    /// at most `MAX_SHARES` times per channel between releases.
+    pub fn share_buffer(&mut self, buffer: Arc<SampleBuffer>, channel: usize) {
+        let channel = &mut self.channels[channel.min(7)];
+        for voice in &mut channel.voices {
+            if !voice.is_active() && !voice.engine.shares_buffer(&buffer) {
+                voice.set_buffer(buffer.clone());
+            }
+        }
+        let previous = std::mem::replace(&mut channel.buffer, buffer);
+        retire(&mut channel.retired, previous);
+    }
+
+    /// Hands `free` every buffer the channels and voices have moved off
+    /// since the last call. Call it off the audio thread, or have `free`
+    /// pass the buffers to one, since this may drop the last reference.
+    pub fn release_retired(&mut self, mut free: impl FnMut(Arc<SampleBuffer>)) {
+        for channel in &mut self.channels {
+            channel.retired.drain(..).for_each(&mut free);
+            for voice in &mut channel.voices {
+                voice.retire_idle_tails();
+                voice.retired.drain(..).for_each(&mut free);
+            }
+        }
+    }
+
+    pub fn share_all_buffers(&mut self, buffers: &[Arc<SampleBuffer>; 8]) {
+        for (i, buffer) in buffers.iter().enumerate() {
+            self.share_buffer(buffer.clone(), i);
+        }
+    }
+
+    /// Starts a voice that isn't tied to a MIDI note.
+    pub fn trigger(&mut self, params: &RamPlayParams, channel: usize) {
+        self.start_voice(params, channel, None);
+    }
+
+    /// Starts a voice for `note`; `release_note` with the same note ends it.
+    pub fn trigger_note(&mut self, params: &RamPlayParams, channel: usize, note: u8) {
+        self.start_voice(params, channel, Some(note));
+    }
+
+    fn start_voice(&mut self, params: &RamPlayParams, channel: usize, note: Option<u8>) {
+        let channel = &mut self.channels[channel.min(7)];
+        let index = channel.allocate_voice(params);
+        self.trigger_count += 1;
+
+        channel.voices[index].start(params, note, self.trigger_count, &channel.buffer);
+        channel.busy |= 1 << index;
+    }
+
+    /// Fades every voice on the channel out and cancels pending retrigs.
+    pub fn stop(&mut self, channel: usize) {
+        for voice in &mut self.channels[channel.min(7)].voices {
+            voice.stop();
+        }
+    }
+
+    /// Note-off for every held voice on the channel.
+    pub fn release(&mut self, channel: usize) {
+        for voice in &mut self.channels[channel.min(7)].voices {
+            if voice.gate_held {
+                voice.release();
+            }
+        }
+    }
+
+    /// Note-off for the voices started by `note`.
+    pub fn release_note(&mut self, channel: usize, note: u8) {
+        for voice in &mut self.channels[channel.min(7)].voices {
+            if voice.gate_held && voice.note == Some(note) {
+                voice.release();
+            }
+        }
+    }
+
+    /// Sets the pitch-bend wheel (-1.0 to 1.0). Scaled by each voice's bend
+    /// range and applied to playing voices immediately.
+    pub fn set_pitch_bend(&mut self, bend: f32, channel: usize) {
+        let channel = channel.min(7);
+        self.channels[channel].pitch_bend = bend.clamp(-1.0, 1.0);
+    }
+
+    pub fn stop_all(&mut self) {
+        for channel in 0..8 {
+            self.stop(channel);
+        }
+    }
+
+    /// Mono render of a channel; stereo buffers are averaged.
+    pub fn process(&mut self, channel: usize) -> f32 {
+        let (left, right) = self.process_stereo(channel);
+        (left + right) * 0.5
+    }
+
+    pub fn process_stereo(&mut self, channel: usize) -> (f32, f32) {
+        let channel = &mut self.channels[channel.min(7)];
+        let mut left = 0.0;
+        let mut right = 0.0;
+        let mut busy = channel.busy;
+        while busy != 0 {
+            let index = busy.trailing_zeros() as usize;
+            busy &= busy - 1;
+            let voice = &mut channel.voices[index];
+            let (l, r) = voice.process(channel.pitch_bend);
+            left += l;
+            right += r;
+            if !voice.is_sounding() {
+                channel.busy &= !(1 << index);
+            }
+        }
+        (left, right)
+    }
+
+    /// Renders one stereo frame from every channel, for the mixer.
+    pub fn process_channels(&mut self) -> [(f32, f32); 8] {
+        std::array::from_fn(|channel| self.process_stereo(channel))
     }
 
     pub fn is_playing(&self, channel: usize) -> bool {
         let channel = channel.min(7);
-        self.is_playing[channel] || self.retrigs_remaining[channel] > 0
+        self.channels[channel].voices.iter().any(Voice::is_active)
+    }
+
+    /// Number of voices sounding or waiting on a retrig.
+    pub fn active_voices(&self, channel: usize) -> usize {
+        let channel = channel.min(7);
+        self.channels[channel]
+            .voices
+            .iter()
+            .filter(|voice| voice.is_active())
+            .count()
     }
 
     pub fn is_any_playing(&self) -> bool {
@@ -203,21 +771,55 @@ impl RamPlay {
     }
 
     pub fn buffer_len(&self, channel: usize) -> usize {
-        let channel = channel.min(7);
-        self.engines[channel].buffer_len()
+        let channel = &self.channels[channel.min(7)];
+        channel.buffer.frames()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::dsp::envelope::envelope_time;
 
     fn make_test_buffer() -> Vec<i16> {
         (0..1000)
-            .map(|i| ((i as f32 / 1000.0 * 32767.0) as i16))
+            .map(|i| ((i as f32 / 1000.0 * 2047.0) as i16))
             .collect()
     }
 
+    /// Largest jump between consecutive samples, starting from silence
+    fn max_step(samples: &[f32]) -> f32 {
+        let mut previous = 0.0;
+        let mut largest: f32 = 0.0;
+        for &sample in samples {
+            largest = largest.max((sample - previous).abs());
+            previous = sample;
+        }
+        largest
+    }
+
+    /// Largest jump between consecutive samples of a mid-note render
+    fn max_jump(samples: &[f32]) -> f32 {
+        samples
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]).abs())
+            .fold(0.0, f32::max)
+    }
+
+    /// Renders a DC buffer at half scale, where any jump is a click
+    fn render_dc(player: &mut RamPlay, samples: usize) -> Vec<f32> {
+        (0..samples).map(|_| player.process(0)).collect()
+    }
+
+    fn dc_params(dclk: i32) -> RamPlayParams {
+        RamPlayParams {
+            hold: 127,
+            vol: 127,
+            dclk,
+            ..Default::default()
+        }
+    }
+
     #[test]
     fn test_trigger_starts_playback() {
         let mut player = RamPlay::new(44100.0);
@@ -254,7 +856,7 @@ mod tests {
             ..Default::default()
         };
         player.trigger(&params, 0);
-        assert_eq!(player.retrigs_remaining[0], 2);
+        assert_eq!(player.channels[0].voices[0].retrigs_remaining, 2);
     }
 
     #[test]
@@ -276,4 +878,638 @@ mod tests {
         assert!(sample0 >= -1.0 && sample0 <= 1.0);
         assert!(sample3 >= -1.0 && sample3 <= 1.0);
     }
+
+    #[test]
+    fn test_interpolation_mode_is_per_channel() {
+        let saw: Vec<i16> = (0..1000).map(|i| ((i % 10) * 400 - 2000) as i16).collect();
+        let mut player = RamPlay::new(44100.0);
+        player.load_buffer(saw.clone(), 0);
+        player.load_buffer(saw, 1);
+
+        let drop = RamPlayParams {
+            pitch: 57,
+            hold: 127,
+            intp: 0,
+            ..Default::default()
+        };
+        let linear = RamPlayParams {
+            pitch: 57,
+            hold: 127,
+            intp: 1,
+            ..Default::default()
+        };
+        player.trigger(&drop, 0);
+        player.trigger(&linear, 1);
+
+        let mut differs = false;
+        for _ in 0..16 {
+            if (player.process(0) - player.process(1)).abs() > 1e-6 {
+                differs = true;
+            }
+        }
+        assert!(differs);
+    }
+
+    #[test]
+    fn test_loop_sustains_while_gate_held() {
+        let mut player = RamPlay::new(44100.0);
+        player.load_buffer(make_test_buffer(), 0);
+        let params = RamPlayParams {
+            loop_mode: 1,
+            lstr: 64,
+            ..Default::default()
+        };
+        player.trigger(&params, 0);
+
+        for _ in 0..5000 {
+            player.process(0);
+        }
+        assert!(player.is_playing(0));
+        assert!(player.channels[0].voices[0].engine.position() < 1000.0);
+    }
+
+    #[test]
+    fn test_loop_release_decays_through_envelope() {
+        let mut player = RamPlay::new(44100.0);
+        player.load_buffer(make_test_buffer(), 0);
+        let params = RamPlayParams {
+            loop_mode: 2,
+            dec: 1,
+            ..Default::default()
+        };
+        player.trigger(&params, 0);
+        for _ in 0..2000 {
+            player.process(0);
+        }
+
+        player.release(0);
+        assert!(player.is_playing(0));
+        for _ in 0..2000 {
+            player.process(0);
+        }
+        assert!(!player.is_playing(0));
+    }
+
+    #[test]
+    fn test_gate_release_without_rel_stops_voice() {
+        let mut player = RamPlay::new(44100.0);
+        player.load_buffer(make_test_buffer(), 0);
+        let params = RamPlayParams {
+            envm: 1,
+            rel: 0,
+            ..Default::default()
+        };
+        player.trigger(&params, 0);
+        player.release(0);
+        assert!(!player.is_playing(0));
+    }
+
+    #[test]
+    fn test_reverse_plays_from_end_to_start() {
+        let mut player = RamPlay::new(44100.0);
+        player.load_buffer(make_test_buffer(), 0);
+        let params = RamPlayParams {
+            strt: 64,
+            hold: 127,
+            plbk: 1,
+            dclk: 0,
+            ..Default::default()
+        };
+        player.trigger(&params, 0);
+
+        let first = player.process(0);
+        let second = player.process(0);
+        assert!(first > second);
+
+        for _ in 0..1000 {
+            player.process(0);
+        }
+        assert!(!player.is_playing(0));
+    }
+
+    #[test]
+    fn test_reverse_retrigger_restarts_from_end() {
+        let mut player = RamPlay::new(44100.0);
+        player.load_buffer(make_test_buffer(), 0);
+        let params = RamPlayParams {
+            hold: 127,
+            plbk: 1,
+            rtrg: 1,
+            rtim: 1,
+            ..Default::default()
+        };
+        player.trigger(&params, 0);
+
+        // RTIM 1 retriggers after ~173 samples
+        for _ in 0..180 {
+            player.process(0);
+        }
+        assert!(player.channels[0].voices[0].engine.position() > 950.0);
+        assert_eq!(player.channels[0].voices[0].engine.direction(), -1.0);
+    }
+
+    #[test]
+    fn test_stereo_buffer_plays_both_sides() {
+        let mut player = RamPlay::new(44100.0);
+        let stereo: Vec<i16> = (0..1000).flat_map(|_| [16000, -16000]).collect();
+        player.load_buffer_with_format(stereo, BufferFormat::Stereo16, 0);
+        assert_eq!(player.buffer_len(0), 1000);
+
+        let params = RamPlayParams {
+            hold: 127,
+            dclk: 0,
+            ..Default::default()
+        };
+        player.trigger(&params, 0);
+        let (left, right) = player.process_stereo(0);
+        assert!(left > 0.3);
+        assert!(right < -0.3);
+    }
+
+    #[test]
+    fn test_slice_for_note() {
+        assert_eq!(slice_for_note(12, 60, 1), Some(0));
+        assert_eq!(slice_for_note(60, 60, 16), Some(0));
+        assert_eq!(slice_for_note(75, 60, 16), Some(15));
+        assert_eq!(slice_for_note(76, 60, 16), None);
+        assert_eq!(slice_for_note(59, 60, 16), None);
+    }
+
+    #[test]
+    fn test_slice_plays_its_own_region() {
+        let mut player = RamPlay::new(44100.0);
+        player.load_buffer(make_test_buffer(), 0);
+        let params = RamPlayParams {
+            hold: 127,
+            slices: 4,
+            slice: 2,
+            ..Default::default()
+        };
+        player.trigger(&params, 0);
+        assert!((player.channels[0].voices[0].engine.position() - 500.0).abs() < 1e-9);
+
+        for _ in 0..300 {
+            player.process(0);
+        }
+        assert!(!player.is_playing(0));
+    }
+
+    #[test]
+    fn test_slice_respects_end_offset() {
+        let mut player = RamPlay::new(44100.0);
+        player.load_buffer(make_test_buffer(), 0);
+        let params = RamPlayParams {
+            strt: 127,
+            end: 127,
+            slices: 2,
+            slice: 0,
+            plbk: 1,
+            ..Default::default()
+        };
+        player.trigger(&params, 0);
+        // Reverse from the slice's END, not the buffer's
+        assert!((player.channels[0].voices[0].engine.position() - 499.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_key_tracking_transposes() {
+        let mut ram_play = RamPlay::new(44100.0);
+        ram_play.load_buffer(make_test_buffer(), 0);
+
+        let params = RamPlayParams {
+            hold: 127,
+            key: 12,
+            ..Default::default()
+        };
+        ram_play.trigger(&params, 0);
+        for _ in 0..10 {
+            ram_play.process(0);
+        }
+        assert!((ram_play.channels[0].voices[0].engine.position() - 20.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_fine_tune_and_pitch_bend() {
+        let mut ram_play = RamPlay::new(44100.0);
+        ram_play.load_buffer(make_test_buffer(), 0);
+
+        // +100 cents and a full bend down over 1 semitone cancel out
+        let params = RamPlayParams {
+            hold: 127,
+            tune: 100,
+            bend: 1,
+            ..Default::default()
+        };
+        ram_play.set_pitch_bend(-1.0, 0);
+        ram_play.trigger(&params, 0);
+        for _ in 0..10 {
+            ram_play.process(0);
+        }
+        assert!((ram_play.channels[0].voices[0].engine.position() - 10.0).abs() < 1e-6);
+
+        // Bend follows the wheel while the voice plays
+        ram_play.set_pitch_bend(0.0, 0);
+        ram_play.process(0);
+        let step = ram_play.channels[0].voices[0].engine.position() - 10.0;
+        assert!((step - semitones_to_ratio(1.0)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_declick_fades_in_start() {
+        let mut player = RamPlay::new(44100.0);
+        player.load_buffer(vec![1024; 2000], 0);
+
+        player.trigger(&dc_params(0), 0);
+        assert!(max_step(&render_dc(&mut player, 200)) > 0.4);
+
+        player.stop(0);
+        render_dc(&mut player, 10);
+        player.trigger(&dc_params(2), 0);
+        let output = render_dc(&mut player, 200);
+        assert!(max_step(&output) < 0.01);
+        assert!((output[199] - 0.5).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_declick_fades_out_on_stop() {
+        let mut player = RamPlay::new(44100.0);
+        player.load_buffer(vec![1024; 2000], 0);
+        player.trigger(&dc_params(2), 0);
+        let mut output = render_dc(&mut player, 200);
+
+        player.release(0);
+        assert!(player.is_playing(0));
+        output.extend(render_dc(&mut player, 200));
+        assert!(!player.is_playing(0));
+        assert!(max_step(&output) < 0.01);
+        assert_eq!(output[399], 0.0);
+    }
+
+    #[test]
+    fn test_declick_fades_out_at_end() {
+        let mut player = RamPlay::new(44100.0);
+        player.load_buffer(vec![1024; 300], 0);
+        player.trigger(&dc_params(2), 0);
+        let output = render_dc(&mut player, 400);
+        assert!(!player.is_playing(0));
+        assert!(max_step(&output) < 0.01);
+
+        // Reverse one-shots fade out approaching STRT
+        let params = RamPlayParams {
+            plbk: 1,
+            ..dc_params(2)
+        };
+        player.trigger(&params, 0);
+        let output = render_dc(&mut player, 400);
+        assert!(!player.is_playing(0));
+        assert!(max_step(&output) < 0.01);
+    }
+
+    #[test]
+    fn test_declick_fades_out_at_end_of_hold() {
+        let mut player = RamPlay::new(44100.0);
+        player.load_buffer(vec![1024; 2000], 0);
+        let params = RamPlayParams {
+            hold: 1,
+            ..dc_params(2)
+        };
+        player.trigger(&params, 0);
+        let output = render_dc(&mut player, 1000);
+        assert!(!player.is_playing(0));
+        assert!(max_step(&output) < 0.01);
+    }
+
+    #[test]
+    fn test_retrigger_crossfades_sounding_voice() {
+        let mut player = RamPlay::new(44100.0);
+        let ramp: Vec<i16> = (0..2000).map(|i| (i / 2) as i16).collect();
+        player.load_buffer(ramp, 0);
+        player.trigger(&dc_params(2), 0);
+        let mut output = render_dc(&mut player, 1000);
+
+        // Restarting jumps the read head from ~0.24 back to 0
+        player.trigger(&dc_params(2), 0);
+        output.extend(render_dc(&mut player, 200));
+        assert!(max_step(&output) < 0.01);
+
+        // Retrigs hand over the same way
+        let params = RamPlayParams {
+            rtrg: 3,
+            rtim: 10,
+            ..dc_params(2)
+        };
+        player.trigger(&params, 0);
+        output.extend(render_dc(&mut player, 2000));
+        assert!(max_step(&output) < 0.01);
+    }
+
+    #[test]
+    fn test_stolen_voice_fades_out_on_its_old_buffer() {
+        let mut player = RamPlay::new(44100.0);
+        player.load_buffer(vec![1024; 2000], 0);
+        let params = RamPlayParams {
+            voices: 1,
+            ..dc_params(2)
+        };
+        player.trigger(&params, 0);
+        let mut output = render_dc(&mut player, 500);
+
+        // The new note plays the inverted buffer; the stolen one keeps
+        // sounding the old one as it fades
+        player.load_buffer(vec![-1024; 2000], 0);
+        player.trigger(&params, 0);
+        let tail = render_dc(&mut player, 200);
+        assert!(tail[0] > 0.45);
+        assert!((tail[199] + 0.5).abs() < 0.001);
+        output.extend(tail);
+        assert!(max_step(&output) < 0.02);
+    }
+
+    #[test]
+    fn test_retrigs_inside_declick_keep_earlier_tails() {
+        let mut player = RamPlay::new(44100.0);
+        let ramp: Vec<i16> = (0..20000).map(|i| (i / 10) as i16).collect();
+        player.load_buffer(ramp, 0);
+        let params = dc_params(20);
+        player.trigger(&params, 0);
+        let mut output = render_dc(&mut player, 4000);
+
+        // Each restart comes long before the last one has faded out
+        for _ in 0..6 {
+            player.trigger(&params, 0);
+            output.extend(render_dc(&mut player, 200));
+        }
+        output.extend(render_dc(&mut player, 1000));
+        assert!(max_step(&output) < 0.01);
+    }
+
+    #[test]
+    fn test_voices_leave_the_busy_set_once_silent() {
+        let mut player = RamPlay::new(44100.0);
+        player.load_buffer(vec![1024; 44100], 0);
+        let params = dc_params(20);
+        player.trigger(&params, 0);
+        render_dc(&mut player, 2000);
+
+        // Both the restart and the earlier start it left fade out
+        player.trigger(&params, 0);
+        player.stop(0);
+        assert_eq!(player.channels[0].voices[0].sounding_tails, 1);
+        assert_ne!(player.channels[0].busy, 0);
+        assert!(render_dc(&mut player, 10)[9] > 0.0);
+
+        render_dc(&mut player, 2000);
+        assert_eq!(player.channels[0].busy, 0);
+        assert_eq!(player.channels[0].voices[0].sounding_tails, 0);
+        assert_eq!(player.process(0), 0.0);
+    }
+
+    #[test]
+    fn test_one_shot_mode_ignores_note_off() {
+        let mut player = RamPlay::new(44100.0);
+        player.load_buffer(vec![1024; 44100], 0);
+        let params = RamPlayParams {
+            envm: 0,
+            ..dc_params(2)
+        };
+        player.trigger(&params, 0);
+        render_dc(&mut player, 200);
+        player.release(0);
+        let output = render_dc(&mut player, 1000);
+        assert!(player.is_playing(0));
+        assert!((output[999] - 0.5).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_gate_mode_releases_on_note_off() {
+        let mut player = RamPlay::new(44100.0);
+        player.load_buffer(vec![1024; 44100], 0);
+        let params = RamPlayParams {
+            rel: 40,
+            dcrv: 1,
+            ..dc_params(2)
+        };
+        player.trigger(&params, 0);
+        let mut output = render_dc(&mut player, 200);
+        player.release(0);
+        output.extend(render_dc(&mut player, 400));
+        assert!(player.is_playing(0));
+        assert!(output[599] < output[399] && output[399] < output[199]);
+
+        let release_samples = (envelope_time(40) * 44100.0) as usize;
+        output.extend(render_dc(&mut player, release_samples));
+        assert!(!player.is_playing(0));
+        assert!(max_step(&output) < 0.01);
+    }
+
+    #[test]
+    fn test_attack_ramps_voice_in() {
+        let mut player = RamPlay::new(44100.0);
+        player.load_buffer(vec![1024; 44100], 0);
+        let params = RamPlayParams {
+            atk: 40,
+            ..dc_params(0)
+        };
+        player.trigger(&params, 0);
+        let attack_samples = (envelope_time(40) * 44100.0) as usize;
+        let output = render_dc(&mut player, attack_samples + 10);
+        assert!(max_step(&output) < 0.01);
+        assert!((output[attack_samples / 2] - 0.25).abs() < 0.01);
+        assert!((output[attack_samples + 5] - 0.5).abs() < 0.001);
+    }
+
+    fn poly_params(voices: i32, steal: i32) -> RamPlayParams {
+        RamPlayParams {
+            poly: 1,
+            voices,
+            steal,
+            ..dc_params(2)
+        }
+    }
+
+    fn sounding_notes(player: &RamPlay) -> Vec<u8> {
+        player.channels[0]
+            .voices
+            .iter()
+            .filter(|voice| voice.is_active())
+            .filter_map(|voice| voice.note)
+            .collect()
+    }
+
+    #[test]
+    fn test_voice_mode_from_param() {
+        assert_eq!(VoiceMode::from_param(0), VoiceMode::Mono);
+        assert_eq!(VoiceMode::from_param(1), VoiceMode::Poly);
+        assert_eq!(StealMode::from_param(0), StealMode::Oldest);
+        assert_eq!(StealMode::from_param(1), StealMode::Quietest);
+    }
+
+    #[test]
+    fn test_poly_voices_overlap() {
+        let mut player = RamPlay::new(44100.0);
+        player.load_buffer(vec![1024; 44100], 0);
+        let params = poly_params(4, 0);
+
+        player.trigger_note(&params, 0, 60);
+        render_dc(&mut player, 200);
+        player.trigger_note(&params, 0, 64);
+        let output = render_dc(&mut player, 200);
+        assert_eq!(player.active_voices(0), 2);
+        assert!((output[199] - 1.0).abs() < 0.001);
+        assert!(max_jump(&output) < 0.01);
+    }
+
+    #[test]
+    fn test_mono_mode_reuses_one_voice() {
+        let mut player = RamPlay::new(44100.0);
+        player.load_buffer(vec![1024; 44100], 0);
+        player.trigger_note(&dc_params(2), 0, 60);
+        player.trigger_note(&dc_params(2), 0, 64);
+        assert_eq!(player.active_voices(0), 1);
+        assert_eq!(sounding_notes(&player), vec![64]);
+    }
+
+    #[test]
+    fn test_steals_oldest_voice() {
+        let mut player = RamPlay::new(44100.0);
+        player.load_buffer(vec![1024; 44100], 0);
+        let params = poly_params(2, 0);
+        for note in [60, 62, 64] {
+            player.trigger_note(&params, 0, note);
+            render_dc(&mut player, 100);
+        }
+        let mut notes = sounding_notes(&player);
+        notes.sort();
+        assert_eq!(notes, vec![62, 64]);
+    }
+
+    #[test]
+    fn test_steals_quietest_voice() {
+        let mut player = RamPlay::new(44100.0);
+        player.load_buffer(vec![1024; 44100], 0);
+        let params = poly_params(2, 1);
+        player.trigger_note(&params, 0, 60);
+        let quiet = RamPlayParams { vol: 10, ..params };
+        player.trigger_note(&quiet, 0, 62);
+        render_dc(&mut player, 100);
+
+        let mut output = render_dc(&mut player, 1);
+        player.trigger_note(&params, 0, 64);
+        output.extend(render_dc(&mut player, 200));
+        let mut notes = sounding_notes(&player);
+        notes.sort();
+        assert_eq!(notes, vec![60, 64]);
+        // The stolen voice crossfades out rather than cutting
+        assert!(max_jump(&output) < 0.01);
+    }
+
+    #[test]
+    fn test_release_note_leaves_other_voices() {
+        let mut player = RamPlay::new(44100.0);
+        player.load_buffer(vec![1024; 44100], 0);
+        let params = poly_params(4, 0);
+        player.trigger_note(&params, 0, 60);
+        player.trigger_note(&params, 0, 64);
+        render_dc(&mut player, 200);
+
+        player.release_note(0, 60);
+        render_dc(&mut player, 200);
+        assert_eq!(sounding_notes(&player), vec![64]);
+    }
+
+    #[test]
+    fn test_voices_share_one_buffer() {
+        let mut player = RamPlay::new(44100.0);
+        player.load_buffer(vec![1024; 44100], 0);
+        let params = poly_params(4, 0);
+        player.trigger_note(&params, 0, 60);
+        player.trigger_note(&params, 0, 64);
+        let voices = &player.channels[0].voices;
+        assert!(voices[0].engine.shares_buffer(&player.channels[0].buffer));
+        assert!(voices[1].engine.shares_buffer(&player.channels[0].buffer));
+    }
+
+    #[test]
+    fn test_process_channels_renders_every_channel() {
+        let mut player = RamPlay::new(44100.0);
+        for channel in [1, 6] {
+            player.load_buffer(vec![1024; 1000], channel);
+            player.trigger(&dc_params(0), channel);
+        }
+        let frames = player.process_channels();
+        for (channel, (left, right)) in frames.iter().enumerate() {
+            let expected = if channel == 1 || channel == 6 {
+                0.5
+            } else {
+                0.0
+            };
+            assert!((left - expected).abs() < 0.001);
+            assert!((right - expected).abs() < 0.001);
+        }
+    }
+
+    #[test]
+    fn test_shared_buffer_playback_does_not_allocate() {
+        let mut player = RamPlay::new(44100.0);
+        let buffer = Arc::new(SampleBuffer::new(4000));
+        player.share_buffer(buffer.clone(), 2);
+        let params = RamPlayParams {
+            poly: 1,
+            voices: 4,
+            ..Default::default()
+        };
+
+        let heap_calls = crate::alloc_check::heap_calls_in(|| {
+            // Written after sharing, the way a recorder fills it
+            for _ in 0..2000 {
+                buffer.push(1024);
+            }
+            player.trigger_note(&params, 2, 60);
+            player.trigger_note(&params, 2, 64);
+            for _ in 0..500 {
+                player.process_channels();
+            }
+            player.release_note(2, 60);
+            player.stop(2);
+        });
+        assert_eq!(heap_calls, 0);
+        assert_eq!(player.buffer_len(2), 2000);
+    }
+
+    #[test]
+    fn test_buffer_swap_mid_note_does_not_free() {
+        // Without declick the voice holds the only reference to the old
+        // buffer; with it, tails do until restarts reuse them
+        for dclk in [0, 2] {
+            let mut player = RamPlay::new(44100.0);
+            player.load_buffer(vec![1024; 4000], 1);
+            let old = Arc::downgrade(&player.channels[1].buffer);
+            let new = Arc::new(SampleBuffer::from_samples(
+                vec![-1024; 4000],
+                BufferFormat::Mono12,
+            ));
+            let params = dc_params(dclk);
+            player.trigger(&params, 1);
+            for _ in 0..100 {
+                player.process_channels();
+            }
+
+            let heap_calls = crate::alloc_check::heap_calls_in(|| {
+                // The sounding voice keeps the old buffer, then moves off it
+                player.share_buffer(new, 1);
+                for _ in 0..=MAX_TAILS {
+                    player.trigger(&params, 1);
+                    for _ in 0..200 {
+                        player.process_channels();
+                    }
+                }
+                player.stop(1);
+            });
+            assert_eq!(heap_calls, 0, "dclk {dclk}");
+
+            assert!(old.upgrade().is_some());
+            player.release_retired(drop);
+            assert!(old.upgrade().is_none());
+        }
+    }
 }
@@ -1,5 +1,132 @@
-use crate::dsp::sample_engine::param_to_normalized;
+use std::sync::Arc;
 
+use crate::dsp::sample_buffer::SampleBuffer;
+use crate::dsp::sample_engine::{param_to_normalized, BufferFormat};
+use crate::machines::memory::{MemoryPolicy, SampleMemory};
+
+/// Longest pre-roll kept while armed
+const MAX_PRE_ROLL_MS: f32 = 100.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecordTrigger {
+    /// Recording starts as soon as it is requested
+    Immediate,
+    /// Recording is armed and starts once the input mix crosses the threshold
+    Threshold,
+}
+
+impl RecordTrigger {
+    /// Convert 0-1 parameter to record trigger
+    /// 0: Immediate, 1: Threshold
+    pub fn from_param(value: i32) -> Self {
+        match value {
+            1 => RecordTrigger::Threshold,
+            _ => RecordTrigger::Immediate,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LengthSync {
+    /// REC length knob as a share of the maximum record time
+    Free,
+    Beats,
+    Bars,
+}
+
+impl LengthSync {
+    /// Convert 0-2 parameter to length sync
+    /// 0: Free, 1: Beats, 2: Bars
+    pub fn from_param(value: i32) -> Self {
+        match value {
+            1 => LengthSync::Beats,
+            2 => LengthSync::Bars,
+            _ => LengthSync::Free,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StartQuantize {
+    Off,
+    Beat,
+    Bar,
+    Pattern,
+}
+
+impl StartQuantize {
+    /// Convert 0-3 parameter to start quantization
+    /// 0: Off, 1: Beat, 2: Bar, 3: Pattern
+    pub fn from_param(value: i32) -> Self {
+        match value {
+            1 => StartQuantize::Beat,
+            2 => StartQuantize::Bar,
+            3 => StartQuantize::Pattern,
+            _ => StartQuantize::Off,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecordSource {
+    /// Main signal comes from the host input
+    Input,
+    /// Main signal is Ultrawave's own main mix
+    Resample,
+}
+
+impl RecordSource {
+    /// Convert 0-1 parameter to record source
+    /// 0: Input, 1: Resample
+    pub fn from_param(value: i32) -> Self {
+        match value {
+            1 => RecordSource::Resample,
+            _ => RecordSource::Input,
+        }
+    }
+}
+
+/// Ultrawave's main mix after the filter, tapped for internal resampling.
+#[derive(Clone, Copy, Default)]
+pub struct ResampleTap {
+    pub mix: (f32, f32),
+    /// Each channel's filtered share of `mix`
+    pub channels: [(f32, f32); 8],
+}
+
+impl ResampleTap {
+    /// The mix as heard by `channel`, optionally leaving its own share out
+    pub fn source_for(&self, channel: usize, exclude_self: bool) -> (f32, f32) {
+        if exclude_self {
+            let (own_l, own_r) = self.channels[channel];
+            (self.mix.0 - own_l, self.mix.1 - own_r)
+        } else {
+            self.mix
+        }
+    }
+}
+
+/// Beats from `position` to the next multiple of `grid`, 0 when already on it.
+pub fn beats_to_boundary(position: f64, grid: f64) -> f64 {
+    if grid <= 0.0 {
+        return 0.0;
+    }
+    let phase = position.rem_euclid(grid);
+    // Treat positions a hair past a boundary as on it
+    if phase < 1e-6 || grid - phase < 1e-6 {
+        0.0
+    } else {
+        grid - phase
+    }
+}
+
+/// Converts a 0-127 threshold parameter to a linear level, -60 dB to 0 dB.
+pub fn threshold_level(value: i32) -> f32 {
+    let db = -60.0 + 60.0 * param_to_normalized(value.clamp(0, 127));
+    10.0_f32.powf(db / 20.0)
+}
+
+#[derive(Clone, Copy)]
 pub struct RamRecordParams {
     pub mlev: i32,
     pub mbal: i32,
@@ -7,6 +134,34 @@ pub struct RamRecordParams {
     pub ibal: i32,
     pub rec_len: i32,
     pub rec_rate: i32,
+    pub fmt: i32,
+    /// 0: immediate, 1: threshold
+    pub trig: i32,
+    pub thr: i32,
+    /// Pre-roll in ms kept ahead of a threshold trigger
+    pub prer: i32,
+    /// Minimum recording time in ms before auto-stop can end it
+    pub hldo: i32,
+    /// Silence in ms below the threshold that stops recording, 0 = off
+    pub asto: i32,
+    /// Sum into the existing buffer instead of replacing it (0: off, 1: on)
+    pub dub: i32,
+    /// Share of the existing buffer kept on each overdub pass (127 = all)
+    pub fdbk: i32,
+    /// Wrap around the loop until stopped instead of ending at the length (0: off, 1: on)
+    pub circ: i32,
+    /// 0: free, 1: beats, 2: bars
+    pub sync: i32,
+    /// Record length in beats or bars when synced
+    pub slen: i32,
+    /// 0: off, 1: beat, 2: bar, 3: pattern
+    pub qnt: i32,
+    /// Pattern length in bars for quantized starts
+    pub plen: i32,
+    /// 0: host input, 1: resample the main mix
+    pub src: i32,
+    /// Leave the recording channel out of the resampled mix (0: off, 1: on)
+    pub xslf: i32,
 }
 
 impl Default for RamRecordParams {
@@ -18,80 +173,400 @@ impl Default for RamRecordParams {
             ibal: 64,
             rec_len: 64,
             rec_rate: 127,
+            fmt: 0,
+            trig: 0,
+            thr: 64,
+            prer: 10,
+            hldo: 100,
+            asto: 0,
+            dub: 0,
+            fdbk: 127,
+            circ: 0,
+            sync: 0,
+            slen: 4,
+            qnt: 0,
+            plen: 4,
+            src: 0,
+            xslf: 1,
         }
     }
 }
 
+/// Ring of the most recent input frames while a channel is armed
+struct PreRoll {
+    frames: Vec<[f32; 2]>,
+    len: usize,
+    head: usize,
+    count: usize,
+}
+
+impl PreRoll {
+    fn new(capacity: usize) -> Self {
+        Self {
+            frames: vec![[0.0; 2]; capacity.max(1)],
+            len: 0,
+            head: 0,
+            count: 0,
+        }
+    }
+
+    fn reset(&mut self, len: usize) {
+        self.len = len.min(self.frames.len());
+        self.head = 0;
+        self.count = 0;
+    }
+
+    fn push(&mut self, frame: [f32; 2]) {
+        if self.len == 0 {
+            return;
+        }
+        self.frames[self.head] = frame;
+        self.head = (self.head + 1) % self.len;
+        self.count = (self.count + 1).min(self.len);
+    }
+
+    /// Oldest to newest
+    fn frame(&self, index: usize) -> [f32; 2] {
+        self.frames[(self.head + self.len - self.count + index) % self.len]
+    }
+}
+
 pub struct RamRecord {
-    buffers: [Vec<i16>; 8],
+    /// Carved out of one pool sized by the memory budget and shared with
+    /// the players
+    buffers: [Arc<SampleBuffer>; 8],
+    /// Samples in the pool
+    pool_samples: usize,
+    /// Start and length in samples of each channel's part of the pool
+    regions: [(usize, usize); 8],
+    /// False once a channel records into a buffer outside the pool
+    pooled: [bool; 8],
+    /// Write positions in frames
     write_positions: [usize; 8],
-    max_length: usize,
-    target_length: usize,
+    target_lengths: [usize; 8],
+    /// Budget limiting how much of the pool recordings may fill
+    memory: SampleMemory<8>,
+    /// Params each channel was started with; levels can be updated live
+    params: [RamRecordParams; 8],
     is_recording: [bool; 8],
+    /// Waiting for the input to cross `thresholds`
+    armed: [bool; 8],
+    thresholds: [f32; 8],
+    pre_rolls: [PreRoll; 8],
+    hold_off_remaining: [usize; 8],
+    /// Frames of silence that stop recording, 0 = never
+    auto_stop_frames: [usize; 8],
+    silence_frames: [usize; 8],
+    overdub: [bool; 8],
+    feedback: [f32; 8],
+    circular: [bool; 8],
+    /// Loop length taken from the existing buffer when overdubbing, 0 = none
+    overdub_lengths: [usize; 8],
+    /// Frames still to wait for a quantized start
+    start_delays: [usize; 8],
+    tempo: f64,
+    /// Quarter-note beats per bar
+    beats_per_bar: f64,
+    /// Song position in beats at the next start, if the host reports one
+    song_position: Option<f64>,
     sample_rate: f32,
-    rec_rate_counter: f32,
-    rec_rate_divisor: f32,
+    rec_rate_counters: [f32; 8],
+    rec_rate_divisors: [f32; 8],
 }
 
 impl RamRecord {
-    const MAX_RECORD_SECONDS: f32 = 10.0;
-
     pub fn new(sample_rate: f32) -> Self {
-        let max_length = (sample_rate * Self::MAX_RECORD_SECONDS) as usize;
+        let memory = SampleMemory::default();
+        let pool_samples = Self::pool_samples(memory.budget());
         Self {
-            buffers: [
-                Vec::with_capacity(max_length),
-                Vec::with_capacity(max_length),
-                Vec::with_capacity(max_length),
-                Vec::with_capacity(max_length),
-                Vec::with_capacity(max_length),
-                Vec::with_capacity(max_length),
-                Vec::with_capacity(max_length),
-                Vec::with_capacity(max_length),
-            ],
+            buffers: SampleBuffer::pool(pool_samples),
+            pool_samples,
+            regions: [(0, 0); 8],
+            pooled: [true; 8],
             write_positions: [0; 8],
-            max_length,
-            target_length: max_length,
+            target_lengths: [0; 8],
+            memory,
+            params: [RamRecordParams::default(); 8],
             is_recording: [false; 8],
+            armed: [false; 8],
+            thresholds: [0.0; 8],
+            pre_rolls: std::array::from_fn(|_| PreRoll::new(Self::pre_roll_capacity(sample_rate))),
+            hold_off_remaining: [0; 8],
+            auto_stop_frames: [0; 8],
+            silence_frames: [0; 8],
+            overdub: [false; 8],
+            feedback: [1.0; 8],
+            circular: [false; 8],
+            overdub_lengths: [0; 8],
+            start_delays: [0; 8],
+            tempo: 120.0,
+            beats_per_bar: 4.0,
+            song_position: None,
             sample_rate,
-            rec_rate_counter: 0.0,
-            rec_rate_divisor: 1.0,
+            rec_rate_counters: [0.0; 8],
+            rec_rate_divisors: [1.0; 8],
         }
     }
 
+    fn pre_roll_capacity(sample_rate: f32) -> usize {
+        (MAX_PRE_ROLL_MS * 0.001 * sample_rate) as usize
+    }
+
+    /// Room for a budget filled with 12-bit samples, the densest format
+    fn pool_samples(budget: usize) -> usize {
+        budget * 8 / BufferFormat::Mono12.bits() as usize
+    }
+
+    /// Sizes the pool to the memory budget from `set_memory` and the
+    /// pre-rolls to `max_sample_rate`. A new pool reallocates every buffer,
+    /// losing the recordings, and players must be handed the new buffers,
+    /// so only call this outside the audio thread.
+    pub fn allocate(&mut self, max_sample_rate: f32) {
+        let pre_roll = Self::pre_roll_capacity(max_sample_rate);
+        if pre_roll > self.pre_rolls[0].frames.len() {
+            self.pre_rolls = std::array::from_fn(|_| PreRoll::new(pre_roll));
+        }
+        let pool_samples = Self::pool_samples(self.memory.budget());
+        if pool_samples == self.pool_samples {
+            return;
+        }
+        self.buffers = SampleBuffer::pool(pool_samples);
+        self.pool_samples = pool_samples;
+        self.regions = [(0, 0); 8];
+        self.pooled = [true; 8];
+        self.write_positions = [0; 8];
+        self.stop_all_recording();
+        self.memory.release_all();
+    }
+
+    /// Doesn't allocate
     pub fn set_sample_rate(&mut self, sample_rate: f32) {
         self.sample_rate = sample_rate;
-        self.max_length = (sample_rate * Self::MAX_RECORD_SECONDS) as usize;
     }
 
-    pub fn start_recording(&mut self, params: &RamRecordParams, clear: bool, channel: usize) {
+    /// Host tempo in BPM and bar length in quarter-note beats
+    pub fn set_tempo(&mut self, tempo: f64, beats_per_bar: f64) {
+        if tempo > 0.0 {
+            self.tempo = tempo;
+        }
+        if beats_per_bar > 0.0 {
+            self.beats_per_bar = beats_per_bar;
+        }
+    }
+
+    /// Song position in beats where the next `start_recording` happens
+    pub fn set_song_position(&mut self, position: Option<f64>) {
+        self.song_position = position;
+    }
+
+    fn beats_to_frames(&self, beats: f64) -> usize {
+        (beats * 60.0 / self.tempo * self.sample_rate as f64) as usize
+    }
+
+    /// Starts recording, or arms the channel when the trigger is set to
+    /// threshold. Returns false when sample memory refuses the recording.
+    pub fn start_recording(
+        &mut self,
+        params: &RamRecordParams,
+        clear: bool,
+        channel: usize,
+    ) -> bool {
         let channel = channel.min(7);
 
-        if clear {
+        // Frames of different widths can't share a buffer
+        let format = BufferFormat::from_param(params.fmt);
+        let overdub = params.dub != 0;
+        if format != self.buffers[channel].format() {
+            self.buffers[channel].set_format(format);
+        }
+        let keep = (!clear || overdub) && !self.buffers[channel].is_empty();
+        if !keep {
             self.buffers[channel].clear();
             self.write_positions[channel] = 0;
+            self.memory.release(channel);
+            self.release_region(channel);
+        }
+        self.params[channel] = *params;
+
+        // Overdub passes start from the top and follow the existing loop
+        self.overdub[channel] = overdub;
+        self.feedback[channel] = param_to_normalized(params.fdbk.clamp(0, 127));
+        self.circular[channel] = params.circ != 0;
+        if overdub {
+            self.write_positions[channel] = 0;
+            self.overdub_lengths[channel] = self.buffer_len(channel);
+        } else {
+            self.overdub_lengths[channel] = 0;
+        }
+
+        let max_length = self.max_length(channel, format);
+        let length = match LengthSync::from_param(params.sync) {
+            // Rec length is a share of the sample memory
+            LengthSync::Free => (param_to_normalized(params.rec_len) * max_length as f32) as usize,
+            LengthSync::Beats => self.beats_to_frames(params.slen.max(1) as f64),
+            LengthSync::Bars => {
+                self.beats_to_frames(params.slen.max(1) as f64 * self.beats_per_bar)
+            }
+        };
+        let length = length.min(max_length).max(1);
+
+        // Overdubs need room for the loop they follow
+        let needed = length.max(self.overdub_lengths[channel]);
+        let mut granted = self.memory.grant(channel, needed, format);
+        if granted > 0 && self.pooled[channel] {
+            let channels = format.channels();
+            granted = self.place(channel, granted * channels, keep) / channels;
+            if granted < needed && self.memory.policy() == MemoryPolicy::Refuse {
+                granted = 0;
+            }
         }
+        if granted == 0 {
+            self.finish(channel);
+            return false;
+        }
+        self.target_lengths[channel] = length.min(granted);
+        self.overdub_lengths[channel] = self.overdub_lengths[channel].min(granted);
+        self.memory
+            .set_used(channel, granted * format.channels(), format);
 
-        let len_normalized = param_to_normalized(params.rec_len);
-        self.target_length = ((len_normalized * Self::MAX_RECORD_SECONDS * self.sample_rate)
-            as usize)
-            .min(self.max_length)
-            .max(1);
+        // Without a song position there is no grid, so start right away
+        let grid = match StartQuantize::from_param(params.qnt) {
+            StartQuantize::Off => 0.0,
+            StartQuantize::Beat => 1.0,
+            StartQuantize::Bar => self.beats_per_bar,
+            StartQuantize::Pattern => params.plen.max(1) as f64 * self.beats_per_bar,
+        };
+        self.start_delays[channel] = match self.song_position {
+            Some(position) => self.beats_to_frames(beats_to_boundary(position, grid)),
+            None => 0,
+        };
 
         let rate_normalized = param_to_normalized(params.rec_rate);
-        self.rec_rate_divisor = 1.0 + (1.0 - rate_normalized) * 15.0;
-        self.rec_rate_counter = 0.0;
+        self.rec_rate_divisors[channel] = 1.0 + (1.0 - rate_normalized) * 15.0;
+        self.rec_rate_counters[channel] = 0.0;
+
+        self.thresholds[channel] = threshold_level(params.thr);
+        self.hold_off_remaining[channel] = self.ms_to_frames(params.hldo, channel);
+        self.auto_stop_frames[channel] = self.ms_to_frames(params.asto, channel);
+        self.silence_frames[channel] = 0;
+
+        match RecordTrigger::from_param(params.trig) {
+            RecordTrigger::Immediate => {
+                self.armed[channel] = false;
+                self.is_recording[channel] = true;
+            }
+            RecordTrigger::Threshold => {
+                let pre_roll = self.ms_to_frames(params.prer, channel);
+                self.pre_rolls[channel].reset(pre_roll);
+                self.armed[channel] = true;
+                self.is_recording[channel] = false;
+            }
+        }
+        true
+    }
+
+    /// Longest recording a channel can make in `format`: all of the
+    /// sample memory, as far as the pool or its buffer has room
+    fn max_length(&self, channel: usize, format: BufferFormat) -> usize {
+        let room = if self.pooled[channel] {
+            self.pool_samples
+        } else {
+            self.buffers[channel].capacity()
+        };
+        let budget = self.memory.budget() * 8 / format.bits() as usize;
+        budget.min(room) / format.channels()
+    }
+
+    /// Gives a channel up to `samples` samples of the pool and returns how
+    /// many it got. A channel keeping its recording grows in place;
+    /// otherwise it moves to the first gap with room, or the largest gap.
+    fn place(&mut self, channel: usize, samples: usize, keep: bool) -> usize {
+        let (start, room) = if keep {
+            let start = self.regions[channel].0;
+            let room = self.room_at(channel, start).max(self.regions[channel].1);
+            (start, room)
+        } else {
+            let ends = self.regions.map(|(start, len)| start + len);
+            let mut best = (0, 0);
+            for start in std::iter::once(0).chain(ends) {
+                let room = self.room_at(channel, start);
+                if room >= samples {
+                    best = (start, room);
+                    break;
+                }
+                if room > best.1 {
+                    best = (start, room);
+                }
+            }
+            best
+        };
+        let len = samples.min(room);
+        self.regions[channel] = (start, len);
+        self.buffers[channel].carve(start, len);
+        len
+    }
 
-        self.is_recording[channel] = true;
+    fn release_region(&mut self, channel: usize) {
+        if self.pooled[channel] {
+            self.regions[channel].1 = 0;
+            self.buffers[channel].carve(self.regions[channel].0, 0);
+        }
+    }
+
+    /// Free samples from `start` up to the next region of another channel
+    /// or the end of the pool
+    fn room_at(&self, channel: usize, start: usize) -> usize {
+        let mut end = self.pool_samples;
+        for (other, &(other_start, len)) in self.regions.iter().enumerate() {
+            if other == channel || len == 0 || !self.pooled[other] {
+                continue;
+            }
+            if (other_start..other_start + len).contains(&start) {
+                return 0;
+            }
+            if other_start >= start {
+                end = end.min(other_start);
+            }
+        }
+        end.saturating_sub(start)
+    }
+
+    /// Recorded frames spanning `ms` at the channel's rec rate
+    fn ms_to_frames(&self, ms: i32, channel: usize) -> usize {
+        (ms.max(0) as f32 * 0.001 * self.sample_rate / self.rec_rate_divisors[channel]) as usize
+    }
+
+    /// Updates a channel's live levels and balance. Length, rate and trigger
+    /// stay as they were at the last start.
+    pub fn set_params(&mut self, params: &RamRecordParams, channel: usize) {
+        let channel = channel.min(7);
+        self.params[channel] = *params;
     }
 
     pub fn stop_recording(&mut self, channel: usize) {
         let channel = channel.min(7);
-        self.is_recording[channel] = false;
+        self.finish(channel);
     }
 
     pub fn stop_all_recording(&mut self) {
-        self.is_recording = [false; 8];
+        for channel in 0..8 {
+            self.finish(channel);
+        }
+    }
+
+    /// Ends a recording and hands memory the buffer didn't use back to the
+    /// budget and the pool
+    fn finish(&mut self, channel: usize) {
+        self.is_recording[channel] = false;
+        self.armed[channel] = false;
+        let buffer = &self.buffers[channel];
+        let len = buffer.len();
+        self.memory.set_used(channel, len, buffer.format());
+        if self.pooled[channel] {
+            let start = self.regions[channel].0;
+            self.regions[channel] = (start, len);
+            buffer.carve(start, len);
+        }
     }
 
     pub fn is_recording(&self, channel: usize) -> bool {
@@ -99,8 +574,43 @@ impl RamRecord {
         self.is_recording[channel]
     }
 
+    /// Whether a quantized start is still waiting for its boundary
+    pub fn is_waiting(&self, channel: usize) -> bool {
+        let channel = channel.min(7);
+        (self.is_recording[channel] || self.armed[channel]) && self.start_delays[channel] > 0
+    }
+
+    pub fn is_armed(&self, channel: usize) -> bool {
+        let channel = channel.min(7);
+        self.armed[channel]
+    }
+
+    /// Whether any channel is recording or armed and needs input
     pub fn is_any_recording(&self) -> bool {
-        self.is_recording.iter().any(|&r| r)
+        self.is_recording
+            .iter()
+            .zip(&self.armed)
+            .any(|(&recording, &armed)| recording || armed)
+    }
+
+    /// Feeds one input frame to every channel using each channel's own params.
+    /// Channels set to resample take their main signal from `tap` instead.
+    pub fn record_all(
+        &mut self,
+        main_l: f32,
+        main_r: f32,
+        input_l: f32,
+        input_r: f32,
+        tap: &ResampleTap,
+    ) {
+        for channel in 0..8 {
+            let params = self.params[channel];
+            let (main_l, main_r) = match RecordSource::from_param(params.src) {
+                RecordSource::Input => (main_l, main_r),
+                RecordSource::Resample => tap.source_for(channel, params.xslf != 0),
+            };
+            self.record_sample(main_l, main_r, input_l, input_r, &params, channel);
+        }
     }
 
     pub fn record_sample(
@@ -114,59 +624,189 @@ impl RamRecord {
     ) {
         let channel = channel.min(7);
 
-        if !self.is_recording[channel] {
+        if !self.is_recording[channel] && !self.armed[channel] {
             return;
         }
 
-        if self.write_positions[channel] >= self.target_length {
-            self.is_recording[channel] = false;
+        if self.start_delays[channel] > 0 {
+            self.start_delays[channel] -= 1;
             return;
         }
 
-        self.rec_rate_counter += 1.0;
-        if self.rec_rate_counter < self.rec_rate_divisor {
+        if self.is_recording[channel]
+            && !self.circular[channel]
+            && self.write_positions[channel] >= self.record_length(channel)
+        {
+            self.finish(channel);
             return;
         }
-        self.rec_rate_counter = 0.0;
 
+        self.rec_rate_counters[channel] += 1.0;
+        if self.rec_rate_counters[channel] < self.rec_rate_divisors[channel] {
+            return;
+        }
+        self.rec_rate_counters[channel] = 0.0;
+
+        let frame = Self::mix_frame(
+            main_l,
+            main_r,
+            input_l,
+            input_r,
+            params,
+            self.buffers[channel].format(),
+        );
+        let level = frame[0].abs().max(frame[1].abs());
+        let threshold = self.thresholds[channel];
+
+        if self.armed[channel] {
+            if level < threshold {
+                self.pre_rolls[channel].push(frame);
+                return;
+            }
+            // Triggered: the pre-roll goes in ahead of the transient
+            self.armed[channel] = false;
+            self.is_recording[channel] = true;
+            for index in 0..self.pre_rolls[channel].count {
+                let pre = self.pre_rolls[channel].frame(index);
+                self.write_frame(channel, pre);
+            }
+        }
+
+        self.write_frame(channel, frame);
+
+        if self.auto_stop_frames[channel] > 0 {
+            if level < threshold {
+                self.silence_frames[channel] += 1;
+            } else {
+                self.silence_frames[channel] = 0;
+            }
+            if self.hold_off_remaining[channel] > 0 {
+                self.hold_off_remaining[channel] -= 1;
+            } else if self.silence_frames[channel] >= self.auto_stop_frames[channel] {
+                self.finish(channel);
+            }
+        }
+    }
+
+    /// Applies levels and balance to one input frame
+    fn mix_frame(
+        main_l: f32,
+        main_r: f32,
+        input_l: f32,
+        input_r: f32,
+        params: &RamRecordParams,
+        format: BufferFormat,
+    ) -> [f32; 2] {
         let mlev = param_to_normalized(params.mlev);
         let ilev = param_to_normalized(params.ilev);
-
         let mbal = param_to_normalized(params.mbal);
-        let main_l_gain = (1.0 - mbal).min(1.0);
-        let main_r_gain = mbal.min(1.0);
-
         let ibal = param_to_normalized(params.ibal);
-        let input_l_gain = (1.0 - ibal).min(1.0);
-        let input_r_gain = ibal.min(1.0);
 
-        let main_mix = (main_l * main_l_gain + main_r * main_r_gain) * mlev;
-        let input_mix = (input_l * input_l_gain + input_r * input_r_gain) * ilev;
-        let combined = (main_mix + input_mix).clamp(-1.0, 1.0);
+        match format {
+            BufferFormat::Mono12 => {
+                let main_l_gain = (1.0 - mbal).min(1.0);
+                let main_r_gain = mbal.min(1.0);
+                let input_l_gain = (1.0 - ibal).min(1.0);
+                let input_r_gain = ibal.min(1.0);
 
-        let sample_12bit = (combined * 2048.0).round().clamp(-2048.0, 2047.0) as i16;
+                let main_mix = (main_l * main_l_gain + main_r * main_r_gain) * mlev;
+                let input_mix = (input_l * input_l_gain + input_r * input_r_gain) * ilev;
+                let combined = (main_mix + input_mix).clamp(-1.0, 1.0);
+                [combined, combined]
+            }
+            BufferFormat::Stereo16 => {
+                // Balance attenuates the opposite side, unity at center
+                let main_l_gain = (2.0 * (1.0 - mbal)).min(1.0);
+                let main_r_gain = (2.0 * mbal).min(1.0);
+                let input_l_gain = (2.0 * (1.0 - ibal)).min(1.0);
+                let input_r_gain = (2.0 * ibal).min(1.0);
+
+                let left = main_l * main_l_gain * mlev + input_l * input_l_gain * ilev;
+                let right = main_r * main_r_gain * mlev + input_r * input_r_gain * ilev;
+                [left.clamp(-1.0, 1.0), right.clamp(-1.0, 1.0)]
+            }
+        }
+    }
 
-        if self.write_positions[channel] < self.buffers[channel].len() {
-            self.buffers[channel][self.write_positions[channel]] = sample_12bit;
+    /// Frames recorded before stopping or, in circular mode, wrapping
+    fn record_length(&self, channel: usize) -> usize {
+        if self.overdub[channel] && self.overdub_lengths[channel] > 0 {
+            self.overdub_lengths[channel]
         } else {
-            self.buffers[channel].push(sample_12bit);
+            self.target_lengths[channel]
+        }
+    }
+
+    fn write_frame(&mut self, channel: usize, frame: [f32; 2]) {
+        if self.write_positions[channel] >= self.record_length(channel) {
+            if !self.circular[channel] {
+                self.finish(channel);
+                return;
+            }
+            self.write_positions[channel] = 0;
+        }
+
+        let buffer = &self.buffers[channel];
+        let format = buffer.format();
+        let channels = format.channels();
+        let base = self.write_positions[channel] * channels;
+        for (offset, &value) in frame.iter().take(channels).enumerate() {
+            let index = base + offset;
+            if index < buffer.len() {
+                let value = if self.overdub[channel] {
+                    let existing = format.normalize(buffer.get(index));
+                    (existing * self.feedback[channel] + value).clamp(-1.0, 1.0)
+                } else {
+                    value
+                };
+                buffer.set(index, format.quantize(value));
+            } else if !buffer.push(format.quantize(value)) {
+                self.finish(channel);
+                return;
+            }
         }
 
         self.write_positions[channel] += 1;
     }
 
+    /// Copy of a channel's recording. Allocates; players should share the
+    /// buffer through `shared_buffer` instead.
     pub fn get_buffer(&self, channel: usize) -> Vec<i16> {
+        let channel = channel.min(7);
+        self.buffers[channel].to_vec()
+    }
+
+    /// Handle to a channel's buffer that follows the recording as it's written
+    pub fn shared_buffer(&self, channel: usize) -> Arc<SampleBuffer> {
         let channel = channel.min(7);
         self.buffers[channel].clone()
     }
 
-    pub fn get_all_buffers(&self) -> &[Vec<i16>; 8] {
+    pub fn get_all_buffers(&self) -> &[Arc<SampleBuffer>; 8] {
         &self.buffers
     }
 
+    /// Records a channel into `buffer` from now on, such as a registry slot
+    /// in shared memory, stopping any recording in progress. Doesn't
+    /// allocate, but frees the old buffer if nothing else shares it.
+    pub fn set_buffer(&mut self, channel: usize, buffer: Arc<SampleBuffer>) {
+        let channel = channel.min(7);
+        self.buffers[channel] = buffer;
+        self.pooled[channel] = false;
+        self.regions[channel] = (0, 0);
+        self.write_positions[channel] = 0;
+        self.finish(channel);
+    }
+
+    pub fn buffer_format(&self, channel: usize) -> BufferFormat {
+        let channel = channel.min(7);
+        self.buffers[channel].format()
+    }
+
+    /// Buffer length in frames
     pub fn buffer_len(&self, channel: usize) -> usize {
         let channel = channel.min(7);
-        self.buffers[channel].len()
+        self.buffers[channel].frames()
     }
 
     pub fn clear(&mut self, channel: usize) {
@@ -174,7 +814,10 @@ impl RamRecord {
         self.buffers[channel].clear();
         self.write_positions[channel] = 0;
         self.is_recording[channel] = false;
-        self.rec_rate_counter = 0.0;
+        self.armed[channel] = false;
+        self.rec_rate_counters[channel] = 0.0;
+        self.memory.release(channel);
+        self.release_region(channel);
     }
 
     pub fn clear_all(&mut self) {
@@ -182,22 +825,47 @@ impl RamRecord {
             self.buffers[i].clear();
             self.write_positions[i] = 0;
             self.is_recording[i] = false;
+            self.armed[i] = false;
+            self.rec_rate_counters[i] = 0.0;
+            self.release_region(i);
         }
-        self.rec_rate_counter = 0.0;
+        self.memory.release_all();
+    }
+
+    /// Sample memory budget in bytes and what to do with recordings that
+    /// don't fit. Doesn't allocate: a budget larger than the pool only
+    /// takes full effect at the next `allocate`.
+    pub fn set_memory(&mut self, budget: usize, policy: MemoryPolicy) {
+        self.memory.set_budget(budget);
+        self.memory.set_policy(policy);
+    }
+
+    /// Bytes of sample memory held by a channel, including the room
+    /// reserved for a recording in progress
+    pub fn memory_used(&self, channel: usize) -> usize {
+        self.memory.used(channel.min(7))
+    }
+
+    /// Bytes of sample memory no channel holds
+    pub fn memory_free(&self) -> usize {
+        self.memory.free()
     }
 
     pub fn recording_progress(&self, channel: usize) -> f32 {
         let channel = channel.min(7);
-        if self.target_length == 0 {
+        let length = self.record_length(channel);
+        if length == 0 {
             return 0.0;
         }
-        self.write_positions[channel] as f32 / self.target_length as f32
+        self.write_positions[channel] as f32 / length as f32
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::dsp::sample_engine::{DitherMode, InterpolationMode, SampleEngine};
+    use crate::machines::memory::HARDWARE_MEMORY_BYTES;
 
     #[test]
     fn test_new_creates_empty_buffers() {
@@ -252,6 +920,37 @@ mod tests {
         assert!(buffer[0] <= 2047 && buffer[0] >= -2048);
     }
 
+    #[test]
+    fn test_full_scale_recording_plays_back_at_full_scale() {
+        let mut rec = RamRecord::new(44100.0);
+        let params = RamRecordParams {
+            rec_rate: 127,
+            mlev: 127,
+            ..Default::default()
+        };
+        rec.start_recording(&params, true, 0);
+        for i in 0..441 {
+            let sample = (i as f32 * std::f32::consts::TAU / 44.1).sin();
+            rec.record_sample(sample, sample, 0.0, 0.0, &params, 0);
+        }
+        rec.stop_recording(0);
+
+        let mut engine = SampleEngine::new(44100.0);
+        engine.set_shared_buffer(rec.shared_buffer(0));
+        engine.trigger(0.0);
+        let mut peak = 0.0f32;
+        for _ in 0..441 {
+            let (left, _) = engine.read_frame_with_processing(
+                1.0,
+                0,
+                InterpolationMode::DropSample,
+                DitherMode::Off,
+            );
+            peak = peak.max(left.abs());
+        }
+        assert!((peak - 1.0).abs() < 0.01);
+    }
+
     #[test]
     fn test_clear_resets_buffer() {
         let mut rec = RamRecord::new(44100.0);
@@ -282,4 +981,495 @@ mod tests {
         assert_eq!(rec.buffer_len(2), 0);
         assert!(rec.buffer_len(3) > 0);
     }
+
+    #[test]
+    fn test_stereo_recording_keeps_image() {
+        let mut rec = RamRecord::new(44100.0);
+        let params = RamRecordParams {
+            rec_rate: 127,
+            mlev: 127,
+            ilev: 0,
+            fmt: 1,
+            ..Default::default()
+        };
+        rec.start_recording(&params, true, 0);
+        rec.record_sample(0.5, -0.25, 0.0, 0.0, &params, 0);
+
+        assert_eq!(rec.buffer_format(0), BufferFormat::Stereo16);
+        assert_eq!(rec.buffer_len(0), 1);
+        let buffer = rec.get_buffer(0);
+        assert_eq!(buffer.len(), 2);
+        let left = BufferFormat::Stereo16.normalize(buffer[0]);
+        let right = BufferFormat::Stereo16.normalize(buffer[1]);
+        assert!((left - 0.5).abs() < 0.01);
+        assert!((right + 0.25).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_format_change_clears_buffer() {
+        let mut rec = RamRecord::new(44100.0);
+        let mono = RamRecordParams::default();
+        rec.start_recording(&mono, true, 0);
+        rec.record_sample(0.5, 0.5, 0.0, 0.0, &mono, 0);
+        rec.record_sample(0.5, 0.5, 0.0, 0.0, &mono, 0);
+        assert_eq!(rec.buffer_len(0), 2);
+
+        let stereo = RamRecordParams {
+            fmt: 1,
+            ..Default::default()
+        };
+        rec.start_recording(&stereo, false, 0);
+        assert_eq!(rec.buffer_len(0), 0);
+    }
+
+    fn threshold_params() -> RamRecordParams {
+        RamRecordParams {
+            rec_rate: 127,
+            mlev: 127,
+            ilev: 0,
+            trig: 1,
+            thr: 64,
+            prer: 1,
+            hldo: 0,
+            asto: 0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_threshold_level_curve() {
+        assert!((threshold_level(0) - 0.001).abs() < 1e-6);
+        assert!((threshold_level(127) - 1.0).abs() < 1e-6);
+        assert_eq!(RecordTrigger::from_param(0), RecordTrigger::Immediate);
+        assert_eq!(RecordTrigger::from_param(1), RecordTrigger::Threshold);
+    }
+
+    #[test]
+    fn test_armed_waits_for_threshold() {
+        let mut rec = RamRecord::new(1000.0);
+        let params = threshold_params();
+        rec.start_recording(&params, true, 0);
+        assert!(rec.is_armed(0));
+        assert!(!rec.is_recording(0));
+
+        for _ in 0..50 {
+            rec.record_sample(0.001, 0.001, 0.0, 0.0, &params, 0);
+        }
+        assert_eq!(rec.buffer_len(0), 0);
+
+        rec.record_sample(0.9, 0.9, 0.0, 0.0, &params, 0);
+        assert!(!rec.is_armed(0));
+        assert!(rec.is_recording(0));
+    }
+
+    #[test]
+    fn test_pre_roll_keeps_frames_before_trigger() {
+        let mut rec = RamRecord::new(10_000.0);
+        // 1 ms pre-roll at 10 kHz is 10 frames
+        let params = threshold_params();
+        rec.start_recording(&params, true, 0);
+        for i in 0..30 {
+            let quiet = 0.0001 * i as f32;
+            rec.record_sample(quiet, quiet, 0.0, 0.0, &params, 0);
+        }
+        rec.record_sample(0.9, 0.9, 0.0, 0.0, &params, 0);
+
+        assert_eq!(rec.buffer_len(0), 11);
+        let buffer = rec.get_buffer(0);
+        // Oldest pre-roll frame is the 20th quiet one, the transient is last
+        let first = BufferFormat::Mono12.normalize(buffer[0]);
+        assert!((first - 0.002).abs() < 0.001);
+        assert!(BufferFormat::Mono12.normalize(buffer[10]) > 0.8);
+    }
+
+    #[test]
+    fn test_auto_stop_after_silence_and_hold_off() {
+        let mut rec = RamRecord::new(1000.0);
+        let params = RamRecordParams {
+            prer: 0,
+            hldo: 50,
+            asto: 10,
+            ..threshold_params()
+        };
+        rec.start_recording(&params, true, 0);
+        rec.record_sample(0.9, 0.9, 0.0, 0.0, &params, 0);
+
+        // Silence inside the hold-off doesn't stop it
+        for _ in 0..40 {
+            rec.record_sample(0.0, 0.0, 0.0, 0.0, &params, 0);
+        }
+        assert!(rec.is_recording(0));
+
+        for _ in 0..20 {
+            rec.record_sample(0.0, 0.0, 0.0, 0.0, &params, 0);
+        }
+        assert!(!rec.is_recording(0));
+        let len = rec.buffer_len(0);
+        assert!((50..=62).contains(&len));
+    }
+
+    #[test]
+    fn test_overdub_sums_with_feedback() {
+        let mut rec = RamRecord::new(1000.0);
+        let params = RamRecordParams {
+            rec_rate: 127,
+            mlev: 127,
+            ilev: 0,
+            mbal: 0,
+            ..Default::default()
+        };
+        rec.start_recording(&params, true, 0);
+        for _ in 0..4 {
+            rec.record_sample(0.4, 0.0, 0.0, 0.0, &params, 0);
+        }
+        rec.stop_recording(0);
+
+        let dub = RamRecordParams {
+            dub: 1,
+            fdbk: 64,
+            ..params
+        };
+        rec.start_recording(&dub, true, 0);
+        for _ in 0..10 {
+            rec.record_sample(0.2, 0.0, 0.0, 0.0, &dub, 0);
+        }
+
+        // The pass follows the existing 4-frame loop and then stops
+        assert_eq!(rec.buffer_len(0), 4);
+        assert!(!rec.is_recording(0));
+        let expected = 0.4 * param_to_normalized(64) + 0.2;
+        for &value in &rec.get_buffer(0) {
+            assert!((BufferFormat::Mono12.normalize(value) - expected).abs() < 0.002);
+        }
+    }
+
+    #[test]
+    fn test_circular_wraps_until_stopped() {
+        let mut rec = RamRecord::new(1000.0);
+        // rec_len 1 is 78 of the 10000 frames this holds
+        rec.set_memory(15000, MemoryPolicy::Truncate);
+        let params = RamRecordParams {
+            rec_rate: 127,
+            mlev: 127,
+            ilev: 0,
+            mbal: 0,
+            rec_len: 1,
+            circ: 1,
+            ..Default::default()
+        };
+        rec.start_recording(&params, true, 0);
+        let length = rec.target_lengths[0];
+        for i in 0..length * 2 + 5 {
+            let value = if i < length { 0.1 } else { 0.5 };
+            rec.record_sample(value, 0.0, 0.0, 0.0, &params, 0);
+        }
+
+        assert!(rec.is_recording(0));
+        assert_eq!(rec.buffer_len(0), length);
+        // The second pass replaced the first
+        let buffer = rec.get_buffer(0);
+        assert!((BufferFormat::Mono12.normalize(buffer[0]) - 0.5).abs() < 0.002);
+        assert!((BufferFormat::Mono12.normalize(buffer[length - 1]) - 0.5).abs() < 0.002);
+    }
+
+    #[test]
+    fn test_beats_to_boundary() {
+        assert_eq!(beats_to_boundary(0.0, 4.0), 0.0);
+        assert!((beats_to_boundary(1.5, 1.0) - 0.5).abs() < 1e-9);
+        assert!((beats_to_boundary(5.0, 4.0) - 3.0).abs() < 1e-9);
+        assert_eq!(beats_to_boundary(3.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn test_synced_length_follows_tempo() {
+        let mut rec = RamRecord::new(1000.0);
+        rec.set_tempo(120.0, 4.0);
+        let params = RamRecordParams {
+            sync: 2,
+            slen: 1,
+            ..Default::default()
+        };
+        rec.start_recording(&params, true, 0);
+        // One bar of 4/4 at 120 BPM is two seconds
+        assert_eq!(rec.target_lengths[0], 2000);
+
+        rec.set_tempo(60.0, 3.0);
+        let params = RamRecordParams {
+            sync: 1,
+            slen: 2,
+            ..Default::default()
+        };
+        rec.start_recording(&params, true, 0);
+        assert_eq!(rec.target_lengths[0], 2000);
+    }
+
+    #[test]
+    fn test_quantized_start_waits_for_boundary() {
+        let mut rec = RamRecord::new(1000.0);
+        rec.set_tempo(120.0, 4.0);
+        rec.set_song_position(Some(3.5));
+        let params = RamRecordParams {
+            rec_rate: 127,
+            qnt: 2,
+            ..Default::default()
+        };
+        rec.start_recording(&params, true, 0);
+        assert!(rec.is_waiting(0));
+
+        // Half a beat at 120 BPM is 250 ms
+        for _ in 0..250 {
+            rec.record_sample(0.5, 0.5, 0.0, 0.0, &params, 0);
+        }
+        assert_eq!(rec.buffer_len(0), 0);
+        assert!(!rec.is_waiting(0));
+        rec.record_sample(0.5, 0.5, 0.0, 0.0, &params, 0);
+        assert_eq!(rec.buffer_len(0), 1);
+    }
+
+    #[test]
+    fn test_channels_keep_own_length_and_rate() {
+        let mut rec = RamRecord::new(1000.0);
+        // Room for 20000 frames of 12-bit mono
+        rec.set_memory(30000, MemoryPolicy::Truncate);
+        let slow = RamRecordParams {
+            rec_rate: 0,
+            rec_len: 64,
+            ..Default::default()
+        };
+        let fast = RamRecordParams {
+            rec_rate: 127,
+            rec_len: 1,
+            ..Default::default()
+        };
+        rec.start_recording(&slow, true, 0);
+        rec.start_recording(&fast, true, 1);
+        // Starting channel 1 leaves channel 0's settings alone
+        assert_eq!(rec.target_lengths[0], 10_078);
+        assert_eq!(rec.target_lengths[1], 157);
+
+        for _ in 0..160 {
+            rec.record_all(0.5, 0.5, 0.0, 0.0, &ResampleTap::default());
+        }
+        // Channel 0 keeps every 16th frame, channel 1 every frame until full
+        assert_eq!(rec.buffer_len(0), 10);
+        assert_eq!(rec.buffer_len(1), 157);
+        assert!(rec.is_recording(0));
+        assert!(!rec.is_recording(1));
+    }
+
+    #[test]
+    fn test_record_all_uses_channel_levels() {
+        let mut rec = RamRecord::new(1000.0);
+        let main_only = RamRecordParams {
+            mlev: 127,
+            ilev: 0,
+            ..Default::default()
+        };
+        let input_only = RamRecordParams {
+            mlev: 0,
+            ilev: 127,
+            ..Default::default()
+        };
+        rec.start_recording(&main_only, true, 0);
+        rec.start_recording(&input_only, true, 1);
+        rec.record_all(0.5, 0.5, -0.25, -0.25, &ResampleTap::default());
+
+        let main = BufferFormat::Mono12.normalize(rec.get_buffer(0)[0]);
+        let input = BufferFormat::Mono12.normalize(rec.get_buffer(1)[0]);
+        assert!((main - 0.5).abs() < 0.01);
+        assert!((input + 0.25).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_resample_source_excludes_self() {
+        let mut rec = RamRecord::new(1000.0);
+        let resample = RamRecordParams {
+            mlev: 127,
+            ilev: 0,
+            src: 1,
+            xslf: 1,
+            ..Default::default()
+        };
+        rec.start_recording(&resample, true, 0);
+        rec.start_recording(
+            &RamRecordParams {
+                xslf: 0,
+                ..resample
+            },
+            true,
+            1,
+        );
+
+        let mut tap = ResampleTap {
+            mix: (0.75, 0.75),
+            ..Default::default()
+        };
+        tap.channels[0] = (0.5, 0.5);
+        tap.channels[2] = (0.25, 0.25);
+        // The host input is ignored by resampling channels
+        rec.record_all(-1.0, -1.0, 0.0, 0.0, &tap);
+
+        let own_excluded = BufferFormat::Mono12.normalize(rec.get_buffer(0)[0]);
+        let full_mix = BufferFormat::Mono12.normalize(rec.get_buffer(1)[0]);
+        assert!((own_excluded - 0.25).abs() < 0.01);
+        assert!((full_mix - 0.75).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_recordings_share_memory_budget() {
+        let mut rec = RamRecord::new(44100.0);
+        let params = RamRecordParams {
+            rec_len: 127,
+            rec_rate: 127,
+            ..Default::default()
+        };
+        // Room for 10000 frames of 12-bit mono
+        rec.set_memory(15000, MemoryPolicy::Truncate);
+        assert!(rec.start_recording(&params, true, 0));
+        for _ in 0..20000 {
+            rec.record_sample(0.5, 0.5, 0.0, 0.0, &params, 0);
+        }
+        assert_eq!(rec.buffer_len(0), 10000);
+        assert!(!rec.is_recording(0));
+        assert_eq!(rec.memory_used(0), 15000);
+        assert_eq!(rec.memory_free(), 0);
+
+        // Nothing left for another channel
+        rec.set_memory(15000, MemoryPolicy::Refuse);
+        assert!(!rec.start_recording(&params, true, 1));
+        assert!(!rec.is_recording(1));
+
+        rec.clear(0);
+        assert_eq!(rec.memory_free(), 15000);
+    }
+
+    #[test]
+    fn test_one_channel_can_fill_the_hardware_memory() {
+        let mut rec = RamRecord::new(44100.0);
+        rec.set_memory(HARDWARE_MEMORY_BYTES, MemoryPolicy::Truncate);
+        rec.allocate(44100.0);
+        let params = RamRecordParams {
+            rec_len: 127,
+            rec_rate: 127,
+            ..Default::default()
+        };
+        assert!(rec.start_recording(&params, true, 0));
+        while rec.is_recording(0) {
+            rec.record_sample(0.5, 0.5, 0.0, 0.0, &params, 0);
+        }
+        let seconds = rec.buffer_len(0) as f32 / 44100.0;
+        assert!((39.0..40.0).contains(&seconds));
+        // Less than a sample left over
+        assert!(rec.memory_free() < 2);
+    }
+
+    #[test]
+    fn test_channels_record_into_separate_parts_of_the_pool() {
+        let mut rec = RamRecord::new(1000.0);
+        // A pool of 10000 12-bit samples
+        rec.set_memory(15000, MemoryPolicy::Truncate);
+        rec.allocate(1000.0);
+        let params = |rec_len| RamRecordParams {
+            rec_len,
+            rec_rate: 127,
+            mlev: 127,
+            ilev: 0,
+            mbal: 0,
+            ..Default::default()
+        };
+        for (channel, value) in [(0, 0.25), (1, 0.5)] {
+            rec.start_recording(&params(127), true, channel);
+            for _ in 0..3000 {
+                rec.record_sample(value, 0.0, 0.0, 0.0, &params(127), channel);
+            }
+            rec.stop_recording(channel);
+        }
+        rec.clear(0);
+
+        // 7000 frames are free, but the largest gap holds 4000
+        rec.set_memory(15000, MemoryPolicy::Refuse);
+        assert!(!rec.start_recording(&params(60), true, 2));
+        assert!(rec.start_recording(&params(50), true, 2));
+        for _ in 0..5000 {
+            rec.record_sample(1.0, 0.0, 0.0, 0.0, &params(50), 2);
+        }
+        assert_eq!(rec.buffer_len(2), 3937);
+        // Channel 1 is untouched
+        let kept = rec.get_buffer(1);
+        assert_eq!(kept.len(), 3000);
+        assert!(kept
+            .iter()
+            .all(|&sample| (BufferFormat::Mono12.normalize(sample) - 0.5).abs() < 0.002));
+    }
+
+    #[test]
+    fn test_stopping_early_frees_reserved_memory() {
+        let mut rec = RamRecord::new(44100.0);
+        let params = RamRecordParams {
+            rec_len: 127,
+            rec_rate: 127,
+            ..Default::default()
+        };
+        rec.start_recording(&params, true, 0);
+        let reserved = rec.memory_used(0);
+        for _ in 0..100 {
+            rec.record_sample(0.5, 0.5, 0.0, 0.0, &params, 0);
+        }
+        rec.stop_recording(0);
+        assert!(rec.memory_used(0) < reserved);
+        assert_eq!(rec.memory_used(0), 150);
+    }
+
+    #[test]
+    fn test_recording_does_not_allocate() {
+        let mut rec = RamRecord::new(44100.0);
+        let player = rec.shared_buffer(3);
+        let params = RamRecordParams {
+            rec_len: 127,
+            rec_rate: 127,
+            fmt: 1,
+            dub: 1,
+            ..Default::default()
+        };
+        let tap = ResampleTap::default();
+
+        let heap_calls = crate::alloc_check::heap_calls_in(|| {
+            rec.start_recording(&params, true, 3);
+            for _ in 0..1000 {
+                rec.record_all(0.5, -0.5, 0.0, 0.0, &tap);
+            }
+            rec.stop_recording(3);
+            // A second pass overdubs what is already there
+            rec.start_recording(&params, false, 3);
+            rec.record_all(0.25, 0.25, 0.0, 0.0, &tap);
+            rec.stop_all_recording();
+            rec.clear(0);
+        });
+        assert_eq!(heap_calls, 0);
+        // Players see the recording through the shared buffer
+        assert_eq!(player.frames(), 1000);
+        assert_eq!(player.format(), BufferFormat::Stereo16);
+    }
+
+    #[test]
+    fn test_records_into_external_buffer() {
+        let mut rec = RamRecord::new(44100.0);
+        let external = Arc::new(SampleBuffer::new(4));
+        rec.set_buffer(2, external.clone());
+        let params = RamRecordParams {
+            rec_len: 127,
+            rec_rate: 127,
+            ..Default::default()
+        };
+        let tap = ResampleTap::default();
+
+        rec.start_recording(&params, true, 2);
+        for _ in 0..10 {
+            rec.record_all(0.5, 0.5, 0.0, 0.0, &tap);
+        }
+        rec.stop_recording(2);
+        // Recording stops when the buffer is full
+        assert_eq!(external.len(), 4);
+        assert!(Arc::ptr_eq(&rec.shared_buffer(2), &external));
+    }
 }
@@ -1,140 +1,340 @@
-use std::sync::{Arc, Mutex, OnceLock};
+use arc_swap::ArcSwap;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
 
-/// Maximum recording length in seconds
-const MAX_RECORD_SECONDS: f32 = 10.0;
+use crate::dsp::sample_buffer::SampleBuffer;
+use crate::dsp::sample_engine::BufferFormat;
+use crate::machines::memory::{AtomicSampleMemory, MemoryPolicy};
+use crate::machines::shared_memory::{self, SharedMemory, SLOT_SAMPLES};
 
-/// Sample rate used for buffer allocation (44.1kHz standard)
-const DEFAULT_SAMPLE_RATE: f32 = 44100.0;
+/// Samples a slot grows by when `write_sample` runs out of room
+const GROW_SAMPLES: usize = 4096;
 
-/// Shared buffer for a single channel (R1-R4)
-#[derive(Clone)]
+/// Immutable snapshot of what a slot (R1-R4) points at. Slots swap whole
+/// snapshots, so readers never see a half-made change.
 pub struct SharedBuffer {
-    /// 12-bit audio samples stored as i16
-    pub samples: Vec<i16>,
-    /// Current write position (for recording)
-    pub write_position: usize,
-    /// Maximum length in samples
-    pub max_length: usize,
+    /// Audio samples, interleaved for stereo formats. May be a Record
+    /// instance's live buffer.
+    pub buffer: Arc<SampleBuffer>,
+    /// Slot generation this snapshot was published as
+    pub generation: u64,
 }
 
 impl SharedBuffer {
-    fn new() -> Self {
-        let max_length = (DEFAULT_SAMPLE_RATE * MAX_RECORD_SECONDS) as usize;
+    /// Layout and resolution of the samples
+    pub fn format(&self) -> BufferFormat {
+        self.buffer.format()
+    }
+
+    /// Get current buffer length in frames
+    pub fn len(&self) -> usize {
+        self.buffer.frames()
+    }
+
+    /// Check if buffer is empty
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+}
+
+fn empty_buffer(format: BufferFormat) -> Arc<SampleBuffer> {
+    let buffer = SampleBuffer::default();
+    buffer.set_format(format);
+    Arc::new(buffer)
+}
+
+struct Slot {
+    snapshot: ArcSwap<SharedBuffer>,
+    /// Mirrors the snapshot's generation so readers can check for changes
+    /// without loading it
+    generation: AtomicU64,
+    /// Next sample `write_sample` writes
+    write_position: AtomicUsize,
+}
+
+impl Slot {
+    fn new(buffer: Arc<SampleBuffer>) -> Self {
         Self {
-            samples: Vec::with_capacity(max_length),
-            write_position: 0,
-            max_length,
+            write_position: AtomicUsize::new(buffer.len()),
+            snapshot: ArcSwap::from_pointee(SharedBuffer {
+                buffer,
+                generation: 0,
+            }),
+            generation: AtomicU64::new(0),
         }
     }
 
-    /// Clear buffer and reset write position
-    pub fn clear(&mut self) {
-        self.samples.clear();
-        self.write_position = 0;
+    fn buffer(&self) -> Arc<SampleBuffer> {
+        self.snapshot.load().buffer.clone()
     }
 
-    /// Get current buffer length
-    pub fn len(&self) -> usize {
-        self.samples.len()
+    /// Swaps in a snapshot of `buffer` under the next generation
+    fn publish(&self, buffer: Arc<SampleBuffer>) {
+        let previous = self.snapshot.rcu(|current| SharedBuffer {
+            buffer: buffer.clone(),
+            generation: current.generation + 1,
+        });
+        self.generation
+            .fetch_max(previous.generation + 1, Ordering::AcqRel);
     }
 
-    /// Check if buffer is empty
-    pub fn is_empty(&self) -> bool {
-        self.samples.is_empty()
+    /// Moves the slot to a copy of its buffer with room for `capacity`
+    /// samples. Allocates.
+    fn grow(&self, capacity: usize) {
+        let buffer = self.buffer();
+        let grown = SampleBuffer::new(capacity);
+        grown.copy_from(&buffer);
+        self.publish(Arc::new(grown));
     }
 }
 
-/// Global registry for shared buffers R1-R4
+/// Global registry for shared buffers R1-R4.
+///
+/// Reading never locks or allocates: check `generation` each block and load
+/// `snapshot` when it changes. Writers swap in a new snapshot at block
+/// boundaries, which allocates, so audio threads hand publishing to a
+/// background task. `write_sample` assumes one writer per slot at a time,
+/// and only stays allocation-free within the room made by `reserve`.
+/// Sample memory is counted with atomics, so no call here locks.
+///
+/// A registry made with `with_shared_memory` keeps its slots in a mapped
+/// file instead, so other processes mapping the same file see the samples
+/// and generations. Its slots have a fixed size and can't point at other
+/// buffers: publishing copies into them, unless the buffer is the slot's
+/// own `shared_memory_buffer`. Sample memory is still counted per process.
 pub struct BufferRegistry {
     /// The four shared buffers (R1, R2, R3, R4)
-    buffers: [Mutex<SharedBuffer>; 4],
+    slots: [Slot; 4],
+    /// Budget limiting how much the four buffers may hold
+    memory: AtomicSampleMemory<4>,
+    shared_memory: Option<SharedMemory>,
 }
 
 impl BufferRegistry {
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         Self {
-            buffers: [
-                Mutex::new(SharedBuffer::new()),
-                Mutex::new(SharedBuffer::new()),
-                Mutex::new(SharedBuffer::new()),
-                Mutex::new(SharedBuffer::new()),
-            ],
+            slots: std::array::from_fn(|_| Slot::new(empty_buffer(BufferFormat::Mono12))),
+            memory: AtomicSampleMemory::default(),
+            shared_memory: None,
         }
     }
 
-    /// Get a clone of the buffer data for reading
-    /// Returns None if the channel is invalid or if lock fails
+    /// A registry whose slots are mapped from the file at `path`, created
+    /// if missing. Fails if the file can't be mapped or has a different
+    /// layout.
+    pub fn with_shared_memory(path: impl AsRef<Path>) -> io::Result<Self> {
+        let shared_memory = SharedMemory::open(path, 4, SLOT_SAMPLES)?;
+        Ok(Self {
+            slots: std::array::from_fn(|channel| {
+                Slot::new(shared_memory.buffer(channel).unwrap_or_default())
+            }),
+            memory: AtomicSampleMemory::default(),
+            shared_memory: Some(shared_memory),
+        })
+    }
+
+    /// The slot's buffer in shared memory, for recording straight into it.
+    /// Returns None without shared memory or if the channel is invalid
+    pub fn shared_memory_buffer(&self, channel: usize) -> Option<Arc<SampleBuffer>> {
+        self.shared_memory
+            .as_ref()
+            .and_then(|shared_memory| shared_memory.buffer(channel))
+    }
+
+    /// Current snapshot of a slot. Lock-free and allocation-free.
+    /// Returns None if the channel is invalid
+    pub fn snapshot(&self, channel: usize) -> Option<Arc<SharedBuffer>> {
+        self.slots
+            .get(channel)
+            .map(|slot| slot.snapshot.load_full())
+    }
+
+    /// Handle to the samples a slot points at, for playing them without a
+    /// copy. Returns None if the channel is invalid
+    pub fn shared_buffer(&self, channel: usize) -> Option<Arc<SampleBuffer>> {
+        self.slots.get(channel).map(Slot::buffer)
+    }
+
+    /// Changes whenever the slot starts pointing at different samples.
+    /// Readers compare it to decide when to load a new `snapshot`. With
+    /// shared memory it also counts changes made by other processes, which
+    /// `snapshot().generation` doesn't.
+    pub fn generation(&self, channel: usize) -> u64 {
+        if let Some(shared_memory) = &self.shared_memory {
+            return shared_memory.generation(channel);
+        }
+        self.slots
+            .get(channel)
+            .map_or(0, |slot| slot.generation.load(Ordering::Acquire))
+    }
+
+    /// Get a copy of the buffer data. Allocates; audio threads should
+    /// play from `snapshot` instead.
+    /// Returns None if the channel is invalid
     pub fn read_buffer(&self, channel: usize) -> Option<Vec<i16>> {
-        if channel >= 4 {
-            return None;
+        self.shared_buffer(channel).map(|buffer| buffer.to_vec())
+    }
+
+    /// Points a slot at `buffer`, typically a Record instance's recording,
+    /// so players follow it as it is written. Sample memory is left to the
+    /// buffer's owner.
+    /// Returns false if the channel is invalid
+    pub fn publish(&self, channel: usize, buffer: Arc<SampleBuffer>) -> bool {
+        let Some(slot) = self.slots.get(channel) else {
+            return false;
+        };
+        slot.write_position.store(buffer.len(), Ordering::Release);
+        self.swap(channel, buffer);
+        self.release_memory(channel);
+        true
+    }
+
+    /// Points a slot at `buffer`. Shared memory slots can't point outside
+    /// the mapping, so they take a copy instead.
+    fn swap(&self, channel: usize, buffer: Arc<SampleBuffer>) {
+        let slot = &self.slots[channel];
+        let Some(shared_memory) = &self.shared_memory else {
+            slot.publish(buffer);
+            return;
+        };
+        let mapped = slot.buffer();
+        if !Arc::ptr_eq(&mapped, &buffer) {
+            mapped.copy_from(&buffer);
         }
-        self.buffers[channel]
-            .lock()
-            .ok()
-            .map(|buf| buf.samples.clone())
+        shared_memory.bump_generation(channel);
     }
 
-    /// Write a sample to the buffer
-    /// Returns true if successful, false if channel is invalid or lock fails
-    pub fn write_sample(&self, channel: usize, sample: i16) -> bool {
-        if channel >= 4 {
+    /// Makes room for `samples` samples in a slot, keeping what it holds,
+    /// so `write_sample` can fill it without allocating. Allocates; call
+    /// it off the audio thread. Shared memory slots are already full size.
+    /// Returns false if the channel is invalid
+    pub fn reserve(&self, channel: usize, samples: usize) -> bool {
+        let Some(slot) = self.slots.get(channel) else {
             return false;
+        };
+        if self.shared_memory.is_none() && slot.buffer().capacity() < samples {
+            slot.grow(samples);
         }
-        if let Ok(mut buf) = self.buffers[channel].lock() {
-            let write_pos = buf.write_position;
-            if write_pos < buf.samples.len() {
-                buf.samples[write_pos] = sample;
-            } else if buf.samples.len() < buf.max_length {
-                buf.samples.push(sample);
-            } else {
-                // Buffer is full
+        true
+    }
+
+    /// Write a sample to the buffer. Lock-free, and allocation-free while
+    /// the slot has room; once it runs out the slot moves to a larger copy,
+    /// which allocates, so audio threads should `reserve` first.
+    /// Returns true if successful, false if channel is invalid or sample
+    /// memory is full
+    pub fn write_sample(&self, channel: usize, sample: i16) -> bool {
+        let Some(slot) = self.slots.get(channel) else {
+            return false;
+        };
+        let buffer = slot.buffer();
+        let write_pos = slot.write_position.load(Ordering::Acquire);
+        let len = buffer.len();
+        if write_pos < len {
+            buffer.set(write_pos, sample);
+        } else {
+            let format = buffer.format();
+            if len >= self.memory.available_samples(channel, format) {
+                // Sample memory is full
                 return false;
             }
-            buf.write_position += 1;
-            true
-        } else {
-            false
+            if !buffer.push(sample) {
+                if self.shared_memory.is_some() {
+                    // Shared memory slots can't grow
+                    return false;
+                }
+                // Out of room: move to a larger copy
+                slot.grow((len * 2).max(GROW_SAMPLES));
+                slot.buffer().push(sample);
+            }
+            self.memory.set_used(channel, len + 1, format);
         }
+        slot.write_position.store(write_pos + 1, Ordering::Release);
+        true
     }
 
-    /// Replace entire buffer contents
+    /// Replace entire buffer contents with 12-bit mono samples
     /// Returns true if successful
     pub fn replace_buffer(&self, channel: usize, samples: Vec<i16>) -> bool {
-        if channel >= 4 {
+        self.replace_buffer_with_format(channel, samples, BufferFormat::Mono12)
+    }
+
+    /// Replace entire buffer contents and format
+    /// Returns true if successful. Samples that don't fit in sample memory
+    /// are dropped, or the whole buffer is refused, depending on the policy.
+    pub fn replace_buffer_with_format(
+        &self,
+        channel: usize,
+        mut samples: Vec<i16>,
+        format: BufferFormat,
+    ) -> bool {
+        let Some(slot) = self.slots.get(channel) else {
             return false;
+        };
+        if let Some(shared_memory) = &self.shared_memory {
+            // Shared memory slots have a fixed size
+            samples.truncate(shared_memory.capacity() / format.channels() * format.channels());
         }
-        if let Ok(mut buf) = self.buffers[channel].lock() {
-            buf.samples = samples;
-            buf.write_position = buf.samples.len();
-            true
-        } else {
-            false
+        let frames = samples.len() / format.channels();
+        let granted = self.memory.grant(channel, frames, format);
+        if granted < frames {
+            if granted == 0 {
+                return false;
+            }
+            samples.truncate(granted * format.channels());
         }
+        self.memory.set_used(channel, samples.len(), format);
+        slot.write_position.store(samples.len(), Ordering::Release);
+        self.swap(
+            channel,
+            Arc::new(SampleBuffer::from_samples(samples, format)),
+        );
+        true
     }
 
-    /// Get current buffer length
-    pub fn buffer_len(&self, channel: usize) -> usize {
+    /// Get the format of a buffer
+    /// Returns Mono12 if the channel is invalid
+    pub fn buffer_format(&self, channel: usize) -> BufferFormat {
+        self.shared_buffer(channel)
+            .map_or(BufferFormat::Mono12, |buffer| buffer.format())
+    }
+
+    /// Set the format for subsequent `write_sample` calls
+    /// Clears the buffer if the format changes
+    pub fn set_buffer_format(&self, channel: usize, format: BufferFormat) -> bool {
         if channel >= 4 {
-            return 0;
+            return false;
         }
-        self.buffers[channel]
-            .lock()
-            .ok()
-            .map(|buf| buf.len())
-            .unwrap_or(0)
+        if self.buffer_format(channel) != format {
+            self.detach(channel, format);
+        }
+        true
     }
 
-    /// Clear a specific buffer
+    /// Get current buffer length in frames
+    pub fn buffer_len(&self, channel: usize) -> usize {
+        self.shared_buffer(channel)
+            .map_or(0, |buffer| buffer.frames())
+    }
+
+    /// Clear a specific buffer. A published recording is left alone; the
+    /// slot just stops pointing at it.
     pub fn clear_buffer(&self, channel: usize) -> bool {
         if channel >= 4 {
             return false;
         }
-        if let Ok(mut buf) = self.buffers[channel].lock() {
-            buf.clear();
-            true
-        } else {
-            false
-        }
+        self.detach(channel, self.buffer_format(channel));
+        true
+    }
+
+    /// Points a slot at a new empty buffer
+    fn detach(&self, channel: usize, format: BufferFormat) {
+        let slot = &self.slots[channel];
+        slot.write_position.store(0, Ordering::Release);
+        self.swap(channel, empty_buffer(format));
+        self.release_memory(channel);
     }
 
     /// Clear all buffers
@@ -144,17 +344,34 @@ impl BufferRegistry {
         }
     }
 
+    fn release_memory(&self, channel: usize) {
+        self.memory.release(channel);
+    }
+
+    /// Set the sample memory budget in bytes and what happens to buffers
+    /// that don't fit
+    pub fn set_memory(&self, budget: usize, policy: MemoryPolicy) {
+        self.memory.set_budget(budget);
+        self.memory.set_policy(policy);
+    }
+
+    /// Bytes of sample memory held by a buffer
+    pub fn memory_used(&self, channel: usize) -> usize {
+        self.memory.used(channel)
+    }
+
+    /// Bytes of sample memory no buffer holds
+    pub fn memory_free(&self) -> usize {
+        self.memory.free()
+    }
+
     /// Reset write position for recording (without clearing data)
     pub fn reset_write_position(&self, channel: usize) -> bool {
-        if channel >= 4 {
+        let Some(slot) = self.slots.get(channel) else {
             return false;
-        }
-        if let Ok(mut buf) = self.buffers[channel].lock() {
-            buf.write_position = 0;
-            true
-        } else {
-            false
-        }
+        };
+        slot.write_position.store(0, Ordering::Release);
+        true
     }
 }
 
@@ -168,6 +385,27 @@ pub fn get_global_registry() -> Arc<BufferRegistry> {
         .clone()
 }
 
+/// Registry for the standalone binaries, which run as separate processes.
+/// Maps its slots from `shared_memory::path()`, falling back to the global
+/// registry if that fails.
+pub fn get_standalone_registry() -> Arc<BufferRegistry> {
+    static STANDALONE_REGISTRY: OnceLock<Arc<BufferRegistry>> = OnceLock::new();
+    STANDALONE_REGISTRY
+        .get_or_init(
+            || match BufferRegistry::with_shared_memory(shared_memory::path()) {
+                Ok(registry) => Arc::new(registry),
+                Err(err) => {
+                    nih_plug::nih_warn!(
+                        "Couldn't map {}: {err}. Samples won't be shared with other processes.",
+                        shared_memory::path().display()
+                    );
+                    get_global_registry()
+                }
+            },
+        )
+        .clone()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -258,4 +496,167 @@ mod tests {
         // Buffer still has data, but write position is reset
         assert_eq!(registry.buffer_len(2), 2);
     }
+
+    #[test]
+    fn test_stereo_buffer_format() {
+        let registry = BufferRegistry::new();
+        assert!(registry.replace_buffer_with_format(
+            1,
+            vec![100, -100, 200, -200],
+            BufferFormat::Stereo16
+        ));
+        assert_eq!(registry.buffer_format(1), BufferFormat::Stereo16);
+        assert_eq!(registry.buffer_len(1), 2);
+        assert_eq!(registry.read_buffer(1).unwrap().len(), 4);
+
+        assert!(registry.set_buffer_format(1, BufferFormat::Mono12));
+        assert_eq!(registry.buffer_len(1), 0);
+    }
+
+    #[test]
+    fn test_buffers_share_memory_budget() {
+        let registry = BufferRegistry::new();
+        registry.set_memory(12, MemoryPolicy::Truncate);
+        // 12 bytes hold eight 12-bit samples
+        assert!(registry.replace_buffer(0, vec![1; 6]));
+        assert_eq!(registry.memory_used(0), 9);
+        assert!(registry.replace_buffer(1, vec![1; 6]));
+        assert_eq!(registry.buffer_len(1), 2);
+        assert!(!registry.write_sample(2, 1));
+        assert_eq!(registry.memory_free(), 0);
+
+        registry.set_memory(12, MemoryPolicy::Refuse);
+        registry.clear_buffer(1);
+        assert!(!registry.replace_buffer(1, vec![1; 4]));
+        assert!(registry.replace_buffer(1, vec![1; 2]));
+        assert_eq!(registry.memory_free(), 0);
+    }
+
+    #[test]
+    fn test_published_buffer_is_followed_live() {
+        let registry = BufferRegistry::new();
+        let recording = Arc::new(SampleBuffer::new(16));
+        let generation = registry.generation(3);
+        assert!(registry.publish(3, recording.clone()));
+        assert_ne!(registry.generation(3), generation);
+
+        // Samples written after publishing show up without republishing
+        let player = registry.shared_buffer(3).unwrap();
+        recording.push(42);
+        assert!(Arc::ptr_eq(&player, &recording));
+        assert_eq!(registry.read_buffer(3).unwrap(), vec![42]);
+
+        // Clearing the slot leaves the recording alone
+        let generation = registry.generation(3);
+        assert!(registry.clear_buffer(3));
+        assert_ne!(registry.generation(3), generation);
+        assert_eq!(registry.buffer_len(3), 0);
+        assert_eq!(recording.len(), 1);
+    }
+
+    #[test]
+    fn test_write_sample_grows_slot() {
+        let registry = BufferRegistry::new();
+        for i in 0..GROW_SAMPLES + 10 {
+            assert!(registry.write_sample(0, i as i16));
+        }
+        let buffer = registry.read_buffer(0).unwrap();
+        assert_eq!(buffer.len(), GROW_SAMPLES + 10);
+        assert_eq!(buffer[GROW_SAMPLES + 9], (GROW_SAMPLES + 9) as i16);
+    }
+
+    #[test]
+    fn test_reserved_slot_is_written_without_allocating() {
+        let registry = BufferRegistry::new();
+        registry.write_sample(1, 7);
+        assert!(registry.reserve(1, GROW_SAMPLES * 2));
+        assert!(!registry.reserve(4, 1));
+        // arc-swap registers each reading thread on first use
+        registry.snapshot(1);
+
+        let heap_calls = crate::alloc_check::heap_calls_in(|| {
+            for i in 1..GROW_SAMPLES * 2 {
+                assert!(registry.write_sample(1, i as i16));
+            }
+        });
+        assert_eq!(heap_calls, 0);
+        let buffer = registry.read_buffer(1).unwrap();
+        assert_eq!(buffer.len(), GROW_SAMPLES * 2);
+        assert_eq!(buffer[0], 7);
+        assert_eq!(registry.memory_used(1), GROW_SAMPLES * 3);
+    }
+
+    #[test]
+    fn test_readers_pick_up_changes_without_allocating() {
+        let registry = BufferRegistry::new();
+        // arc-swap registers each reading thread on first use
+        registry.snapshot(0);
+        let seen = registry.generation(0);
+        registry.replace_buffer(0, vec![1, 2, 3]);
+
+        let mut snapshot = None;
+        let heap_calls = crate::alloc_check::heap_calls_in(|| {
+            if registry.generation(0) != seen {
+                snapshot = registry.snapshot(0);
+            }
+        });
+        assert_eq!(heap_calls, 0);
+        let snapshot = snapshot.unwrap();
+        assert_eq!(snapshot.generation, registry.generation(0));
+        assert_eq!(snapshot.len(), 3);
+
+        // A snapshot stays intact after the slot moves on
+        registry.replace_buffer(0, vec![4]);
+        assert_eq!(snapshot.buffer.to_vec(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_generations_only_move_forward() {
+        let registry = Arc::new(BufferRegistry::new());
+        let writer = {
+            let registry = registry.clone();
+            std::thread::spawn(move || {
+                for len in 1..200 {
+                    registry.replace_buffer(2, vec![0; len]);
+                }
+            })
+        };
+
+        let mut last = 0;
+        while !writer.is_finished() {
+            // The counter is bumped after the swap, so it never runs ahead
+            let generation = registry.generation(2);
+            let snapshot = registry.snapshot(2).unwrap();
+            assert!(snapshot.generation >= generation);
+            assert!(snapshot.generation >= last);
+            last = snapshot.generation;
+        }
+        writer.join().unwrap();
+        assert_eq!(registry.snapshot(2).unwrap().generation, 199);
+    }
+
+    #[test]
+    fn test_shared_memory_registries_see_each_other() {
+        let path = std::env::temp_dir().join(format!("ultrawave-registry-{}", std::process::id()));
+        let recorder = BufferRegistry::with_shared_memory(&path).unwrap();
+        let player = BufferRegistry::with_shared_memory(&path).unwrap();
+
+        let generation = player.generation(1);
+        let recording = recorder.shared_memory_buffer(1).unwrap();
+        assert!(recorder.publish(1, recording.clone()));
+        assert_ne!(player.generation(1), generation);
+
+        // Written straight into the mapping, so no republishing is needed
+        recording.push(42);
+        assert_eq!(player.read_buffer(1).unwrap(), vec![42]);
+
+        // Other buffers are copied in
+        assert!(recorder.replace_buffer_with_format(2, vec![1, -1], BufferFormat::Stereo16));
+        assert_eq!(player.buffer_format(2), BufferFormat::Stereo16);
+        assert_eq!(player.buffer_len(2), 1);
+        assert!(player.clear_buffer(2));
+        assert_eq!(recorder.buffer_len(2), 0);
+
+        std::fs::remove_file(path).unwrap();
+    }
 }
@@ -0,0 +1,271 @@
+use memmap2::MmapMut;
+use std::fs::OpenOptions;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::ptr::{addr_of_mut, NonNull};
+use std::sync::atomic::{AtomicI16, AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::dsp::sample_buffer::{BufferHeader, SampleBuffer};
+
+/// File the standalone recorder and player map their slots from on Linux,
+/// where /dev/shm keeps it in memory
+#[cfg(target_os = "linux")]
+pub const DEFAULT_PATH: &str = "/dev/shm/ultrawave";
+
+/// File name used in the temp directory on other systems
+#[cfg(not(target_os = "linux"))]
+const DEFAULT_NAME: &str = "ultrawave";
+
+/// Environment variable overriding the default path
+pub const PATH_VARIABLE: &str = "ULTRAWAVE_SHM";
+
+/// Room per slot in samples: ten seconds of stereo at 96 kHz, the longest
+/// recording Record makes
+pub const SLOT_SAMPLES: usize = 2 * 96_000 * 10;
+
+const MAGIC: u32 = u32::from_le_bytes(*b"UWRB");
+const VERSION: u32 = 2;
+
+/// The file header, slot headers and sample regions each start on a cache
+/// line, which also keeps every atomic aligned
+const ALIGN: usize = 64;
+
+#[repr(C)]
+struct FileHeader {
+    /// Stored last, once the rest of the header is filled in
+    magic: AtomicU32,
+    version: AtomicU32,
+    slots: AtomicU32,
+    capacity: AtomicU64,
+}
+
+#[repr(C)]
+struct SlotHeader {
+    /// Bumped whenever the slot starts holding a different recording
+    generation: AtomicU64,
+    buffer: BufferHeader,
+}
+
+const _: () = assert!(std::mem::size_of::<FileHeader>() <= ALIGN);
+const _: () = assert!(std::mem::size_of::<SlotHeader>() <= ALIGN);
+
+/// Path of the standalone binaries' shared memory file: `PATH_VARIABLE` if
+/// set, otherwise `DEFAULT_PATH` on Linux and a file in the temp directory
+/// elsewhere
+pub fn path() -> PathBuf {
+    std::env::var_os(PATH_VARIABLE).map_or_else(default_path, PathBuf::from)
+}
+
+#[cfg(target_os = "linux")]
+fn default_path() -> PathBuf {
+    PathBuf::from(DEFAULT_PATH)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn default_path() -> PathBuf {
+    std::env::temp_dir().join(DEFAULT_NAME)
+}
+
+/// Sample slots mapped from a file, so separate processes read and write
+/// the same buffers.
+///
+/// Layout, each part aligned to 64 bytes: a header holding the magic
+/// number, version, slot count and slot capacity, then per slot a header
+/// with the generation counter, length and format, followed by the
+/// samples. A fresh file reads as empty 12-bit mono slots. Every process
+/// mapping the file must be the same build, and the file must not be
+/// truncated while mapped.
+pub struct SharedMemory {
+    buffers: Vec<Arc<SampleBuffer>>,
+    generations: Vec<NonNull<AtomicU64>>,
+    capacity: usize,
+}
+
+// Safety: the generation counters point into the mapping the buffers keep
+// alive, and are atomic
+unsafe impl Send for SharedMemory {}
+unsafe impl Sync for SharedMemory {}
+
+impl SharedMemory {
+    /// Maps `slots` slots of `capacity` samples from `path`, creating the
+    /// file if it doesn't exist. Fails if an existing file has a different
+    /// layout.
+    pub fn open(path: impl AsRef<Path>, slots: usize, capacity: usize) -> io::Result<Self> {
+        let stride = Self::stride(capacity);
+        let size = (ALIGN + slots * stride) as u64;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        match file.metadata()?.len() {
+            0 => file.set_len(size)?,
+            len if len != size => return Err(Self::layout_error()),
+            _ => {}
+        }
+
+        // Safety: the file is only accessed through atomics, by processes
+        // that agree on its layout
+        let mut map = unsafe { MmapMut::map_mut(&file)? };
+        let base = map.as_mut_ptr();
+
+        // Safety: the mapping is at least ALIGN bytes and page aligned
+        let header = unsafe { &*(base as *const FileHeader) };
+        match header.magic.load(Ordering::Acquire) {
+            0 => {
+                header.version.store(VERSION, Ordering::Relaxed);
+                header.slots.store(slots as u32, Ordering::Relaxed);
+                header.capacity.store(capacity as u64, Ordering::Relaxed);
+                header.magic.store(MAGIC, Ordering::Release);
+            }
+            MAGIC
+                if header.version.load(Ordering::Relaxed) == VERSION
+                    && header.slots.load(Ordering::Relaxed) == slots as u32
+                    && header.capacity.load(Ordering::Relaxed) == capacity as u64 => {}
+            _ => return Err(Self::layout_error()),
+        }
+
+        let map: Arc<MmapMut> = Arc::new(map);
+        let mut buffers = Vec::with_capacity(slots);
+        let mut generations = Vec::with_capacity(slots);
+        for slot in 0..slots {
+            // Safety: both regions lie inside the mapping, per `stride`
+            unsafe {
+                let slot_header = base.add(ALIGN + slot * stride) as *mut SlotHeader;
+                let samples = base.add(ALIGN + slot * stride + ALIGN) as *mut AtomicI16;
+                generations.push(NonNull::new_unchecked(addr_of_mut!(
+                    (*slot_header).generation
+                )));
+                buffers.push(Arc::new(SampleBuffer::from_raw_parts(
+                    NonNull::new_unchecked(addr_of_mut!((*slot_header).buffer)),
+                    NonNull::slice_from_raw_parts(NonNull::new_unchecked(samples), capacity),
+                    map.clone(),
+                )));
+            }
+        }
+
+        Ok(Self {
+            buffers,
+            generations,
+            capacity,
+        })
+    }
+
+    /// Bytes from one slot header to the next
+    fn stride(capacity: usize) -> usize {
+        ALIGN + (capacity * std::mem::size_of::<i16>()).next_multiple_of(ALIGN)
+    }
+
+    fn layout_error() -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "shared memory file has a different layout",
+        )
+    }
+
+    pub fn slots(&self) -> usize {
+        self.buffers.len()
+    }
+
+    /// Room per slot in samples
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The slot's samples. Writes show up in every process at once.
+    pub fn buffer(&self, slot: usize) -> Option<Arc<SampleBuffer>> {
+        self.buffers.get(slot).cloned()
+    }
+
+    /// The slot's generation counter, shared by every process
+    pub fn generation(&self, slot: usize) -> u64 {
+        self.generations.get(slot).map_or(0, |generation| {
+            // Safety: see the Send and Sync impls
+            unsafe { generation.as_ref() }.load(Ordering::Acquire)
+        })
+    }
+
+    /// Tells readers in every process that the slot holds a new recording
+    pub fn bump_generation(&self, slot: usize) {
+        if let Some(generation) = self.generations.get(slot) {
+            // Safety: see the Send and Sync impls
+            unsafe { generation.as_ref() }.fetch_add(1, Ordering::AcqRel);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dsp::sample_engine::BufferFormat;
+    use std::io::{Seek, SeekFrom, Write};
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("ultrawave-{name}-{}", std::process::id()))
+    }
+
+    #[test]
+    fn test_mappings_share_samples_and_generations() {
+        let path = temp_path("shm-share");
+        let writer = SharedMemory::open(&path, 2, 8).unwrap();
+        let reader = SharedMemory::open(&path, 2, 8).unwrap();
+
+        let buffer = writer.buffer(1).unwrap();
+        buffer.set_format(BufferFormat::Stereo16);
+        buffer.push(5);
+        buffer.push(-5);
+        writer.bump_generation(1);
+
+        let seen = reader.buffer(1).unwrap();
+        assert_eq!(seen.format(), BufferFormat::Stereo16);
+        assert_eq!(seen.to_vec(), vec![5, -5]);
+        assert_eq!(reader.generation(1), 1);
+        assert!(reader.buffer(0).unwrap().is_empty());
+        assert_eq!(reader.generation(0), 0);
+
+        // Slots stop at their capacity
+        for _ in 0..10 {
+            buffer.push(1);
+        }
+        assert_eq!(seen.len(), 8);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_length_reads_as_full() {
+        let path = temp_path("shm-corrupt");
+        let memory = SharedMemory::open(&path, 2, 8).unwrap();
+        let buffer = memory.buffer(1).unwrap();
+        buffer.push(3);
+
+        // Another process writes a length past the end of the slot
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        let offset = ALIGN + SharedMemory::stride(8) + std::mem::offset_of!(SlotHeader, buffer);
+        file.seek(SeekFrom::Start(offset as u64)).unwrap();
+        file.write_all(&(1usize << 40).to_ne_bytes()).unwrap();
+        drop(file);
+
+        let reopened = SharedMemory::open(&path, 2, 8).unwrap();
+        for buffer in [buffer, reopened.buffer(1).unwrap()] {
+            assert_eq!(buffer.len(), 8);
+            assert_eq!(buffer.get(100), 0);
+            assert_eq!(buffer.to_vec()[0], 3);
+            assert!(!buffer.push(1));
+        }
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_layout_mismatch_is_refused() {
+        let path = temp_path("shm-layout");
+        let _memory = SharedMemory::open(&path, 4, 16).unwrap();
+        assert!(SharedMemory::open(&path, 4, 32).is_err());
+        // Same file size, different header
+        assert!(SharedMemory::open(&path, 2, 64).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
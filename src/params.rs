@@ -18,6 +18,54 @@ pub struct RamRecordParams {
     pub rec_len: IntParam,
     #[id = "rec_rate"]
     pub rec_rate: IntParam,
+    /// Buffer format (0: 12-bit mono, 1: 16-bit stereo)
+    #[id = "fmt"]
+    pub fmt: IntParam,
+    /// Record trigger (0: immediate, 1: input threshold)
+    #[id = "trig"]
+    pub trig: IntParam,
+    /// Trigger and silence threshold, -60 dB to 0 dB
+    #[id = "thr"]
+    pub thr: IntParam,
+    /// Pre-roll in ms kept ahead of a threshold trigger
+    #[id = "prer"]
+    pub prer: IntParam,
+    /// Minimum recording time in ms before auto-stop
+    #[id = "hldo"]
+    pub hldo: IntParam,
+    /// Silence in ms that stops recording (0: off)
+    #[id = "asto"]
+    pub asto: IntParam,
+    /// Overdub onto the existing buffer (0: replace, 1: overdub)
+    #[id = "dub"]
+    pub dub: IntParam,
+    /// Existing material kept on each overdub pass
+    #[id = "fdbk"]
+    pub fdbk: IntParam,
+    /// Circular recording that wraps until stopped (0: off, 1: on)
+    #[id = "circ"]
+    pub circ: IntParam,
+    /// Record length unit (0: free, 1: beats, 2: bars)
+    #[id = "sync"]
+    pub sync: IntParam,
+    /// Record length in beats or bars when synced
+    #[id = "slen"]
+    pub slen: IntParam,
+    /// Start quantization (0: off, 1: beat, 2: bar, 3: pattern)
+    #[id = "qnt"]
+    pub qnt: IntParam,
+    /// Pattern length in bars
+    #[id = "plen"]
+    pub plen: IntParam,
+    /// Main signal source (0: main input, 1: resample the main mix)
+    #[id = "src"]
+    pub src: IntParam,
+    /// Leave the recording channel out of the resampled mix
+    #[id = "xslf"]
+    pub xslf: IntParam,
+    /// Shared slot the standalone Record plugin publishes to (0-3: R1-R4)
+    #[id = "rslt"]
+    pub slot: IntParam,
 }
 
 impl Default for RamRecordParams {
@@ -31,6 +79,22 @@ impl Default for RamRecordParams {
             cue2: IntParam::new("CUE2", 0, IntRange::Linear { min: 0, max: 127 }),
             rec_len: IntParam::new("Rec Length", 64, IntRange::Linear { min: 0, max: 127 }),
             rec_rate: IntParam::new("Rec Rate", 127, IntRange::Linear { min: 0, max: 127 }),
+            fmt: IntParam::new("Buffer Format", 0, IntRange::Linear { min: 0, max: 1 }),
+            trig: IntParam::new("Rec Trigger", 0, IntRange::Linear { min: 0, max: 1 }),
+            thr: IntParam::new("Threshold", 64, IntRange::Linear { min: 0, max: 127 }),
+            prer: IntParam::new("Pre-Roll", 10, IntRange::Linear { min: 0, max: 100 }),
+            hldo: IntParam::new("Hold-Off", 100, IntRange::Linear { min: 0, max: 2000 }),
+            asto: IntParam::new("Auto Stop", 0, IntRange::Linear { min: 0, max: 2000 }),
+            dub: IntParam::new("Overdub", 0, IntRange::Linear { min: 0, max: 1 }),
+            fdbk: IntParam::new("Feedback", 127, IntRange::Linear { min: 0, max: 127 }),
+            circ: IntParam::new("Circular", 0, IntRange::Linear { min: 0, max: 1 }),
+            sync: IntParam::new("Length Sync", 0, IntRange::Linear { min: 0, max: 2 }),
+            slen: IntParam::new("Sync Length", 4, IntRange::Linear { min: 1, max: 64 }),
+            qnt: IntParam::new("Start Quantize", 0, IntRange::Linear { min: 0, max: 3 }),
+            plen: IntParam::new("Pattern Length", 4, IntRange::Linear { min: 1, max: 16 }),
+            src: IntParam::new("Rec Source", 0, IntRange::Linear { min: 0, max: 1 }),
+            xslf: IntParam::new("Exclude Self", 1, IntRange::Linear { min: 0, max: 1 }),
+            slot: IntParam::new("Slot", 0, IntRange::Linear { min: 0, max: 3 }),
         }
     }
 }
@@ -53,6 +117,63 @@ pub struct RamPlayParams {
     pub rtim: IntParam,
     #[id = "srr"]
     pub srr: IntParam,
+    /// Interpolation mode (0: drop-sample, 1: linear, 2: cubic, 3: sinc)
+    #[id = "intp"]
+    pub intp: IntParam,
+    /// Loop mode (0: off, 1: loop, 2: ping-pong)
+    #[id = "loop"]
+    pub loop_mode: IntParam,
+    #[id = "lstr"]
+    pub lstr: IntParam,
+    /// Playback direction (0: forward, 1: reverse)
+    #[id = "plbk"]
+    pub plbk: IntParam,
+    /// Number of equal slices the buffer is split into (1-128)
+    #[id = "slic"]
+    pub slices: IntParam,
+    /// MIDI note that plays the first slice
+    #[id = "sbas"]
+    pub slice_base: IntParam,
+    /// SRR bit-depth truncation (0: undithered, 1: dithered)
+    #[id = "dith"]
+    pub dith: IntParam,
+    /// Chromatic key tracking (0: off, 1: on); ignored while slicing
+    #[id = "ktrk"]
+    pub ktrk: IntParam,
+    /// Note that plays at the PTCH setting when key tracking
+    #[id = "root"]
+    pub root: IntParam,
+    /// Fine tune in cents
+    #[id = "tune"]
+    pub tune: IntParam,
+    /// Pitch-bend range in semitones
+    #[id = "bend"]
+    pub bend: IntParam,
+    /// Fade time in ms at start, stop, END and retrig
+    #[id = "dclk"]
+    pub dclk: IntParam,
+    #[id = "atk"]
+    pub atk: IntParam,
+    #[id = "rel"]
+    pub rel: IntParam,
+    /// Decay and release curve (0: linear, 1: exponential)
+    #[id = "dcrv"]
+    pub dcrv: IntParam,
+    /// Envelope mode (0: one-shot, 1: gate)
+    #[id = "envm"]
+    pub envm: IntParam,
+    /// Voice mode (0: mono, 1: poly)
+    #[id = "poly"]
+    pub poly: IntParam,
+    /// Poly voice pool size
+    #[id = "vcs"]
+    pub voices: IntParam,
+    /// Voice stealing (0: oldest, 1: quietest)
+    #[id = "stel"]
+    pub steal: IntParam,
+    /// Shared slot the standalone Play plugin follows (0-3: R1-R4)
+    #[id = "pslt"]
+    pub slot: IntParam,
 }
 
 impl Default for RamPlayParams {
@@ -70,6 +191,33 @@ impl Default for RamPlayParams {
                 0,
                 IntRange::Linear { min: 0, max: 127 },
             ),
+            intp: IntParam::new("Interpolation", 1, IntRange::Linear { min: 0, max: 3 }),
+            loop_mode: IntParam::new("Loop Mode", 0, IntRange::Linear { min: 0, max: 2 }),
+            lstr: IntParam::new("Loop Start", 0, IntRange::Linear { min: 0, max: 127 }),
+            plbk: IntParam::new("Playback", 0, IntRange::Linear { min: 0, max: 1 }),
+            slices: IntParam::new("Slices", 1, IntRange::Linear { min: 1, max: 128 }),
+            slice_base: IntParam::new("Slice Base Note", 60, IntRange::Linear { min: 0, max: 127 }),
+            dith: IntParam::new("SRR Dither", 0, IntRange::Linear { min: 0, max: 1 }),
+            ktrk: IntParam::new("Key Track", 1, IntRange::Linear { min: 0, max: 1 }),
+            root: IntParam::new("Root Note", 60, IntRange::Linear { min: 0, max: 127 }),
+            tune: IntParam::new(
+                "Fine Tune",
+                0,
+                IntRange::Linear {
+                    min: -100,
+                    max: 100,
+                },
+            ),
+            bend: IntParam::new("Bend Range", 2, IntRange::Linear { min: 0, max: 24 }),
+            dclk: IntParam::new("Declick", 2, IntRange::Linear { min: 0, max: 20 }),
+            atk: IntParam::new("Attack", 0, IntRange::Linear { min: 0, max: 127 }),
+            rel: IntParam::new("Release", 0, IntRange::Linear { min: 0, max: 127 }),
+            dcrv: IntParam::new("Decay Curve", 0, IntRange::Linear { min: 0, max: 1 }),
+            envm: IntParam::new("Envelope Mode", 1, IntRange::Linear { min: 0, max: 1 }),
+            poly: IntParam::new("Voice Mode", 0, IntRange::Linear { min: 0, max: 1 }),
+            voices: IntParam::new("Voices", 8, IntRange::Linear { min: 1, max: 16 }),
+            steal: IntParam::new("Voice Steal", 0, IntRange::Linear { min: 0, max: 1 }),
+            slot: IntParam::new("Slot", 0, IntRange::Linear { min: 0, max: 3 }),
         }
     }
 }
@@ -84,6 +232,10 @@ pub struct UltrawaveParams {
     #[id = "chan"]
     pub channel: IntParam,
 
+    /// Engine clock (0: host rate, 1: fixed 44.1 kHz)
+    #[id = "clk"]
+    pub clock: IntParam,
+
     #[nested(group = "RAM Record")]
     pub record: RamRecordParams,
 
@@ -98,11 +250,32 @@ pub struct UltrawaveParams {
     #[id = "fltw"]
     pub fltw: IntParam,
 
+    /// Notes trigger the selected channel (0) or RAM channel = MIDI channel (1)
+    #[id = "nrte"]
+    pub note_routing: IntParam,
+
+    /// MIDI note that records into its RAM channel instead of playing
+    #[id = "rnot"]
+    pub record_note: IntParam,
+    /// Editor record switch for the selected channel (0: stop, 1: record)
+    #[id = "rec"]
+    pub record_switch: IntParam,
+
+    /// Sample memory budget shared by all RAM channels, in MB. Recordings
+    /// are carved out of a pool this size, resized when the plugin is
+    /// activated.
+    #[id = "mem"]
+    pub memory_size: IntParam,
+    /// Use the MD's 2.5 MB sample memory instead of `memory_size`
+    #[id = "mdmem"]
+    pub md_memory: IntParam,
+    /// Recordings that don't fit (0: truncate, 1: refuse)
+    #[id = "mful"]
+    pub memory_full: IntParam,
+
     // Output
-    #[id = "vol"]
-    pub vol: IntParam,
-    #[id = "pan"]
-    pub pan: IntParam,
+    #[nested(array, group = "Mixer")]
+    pub mixer: [MixerChannelParams; 8],
 }
 
 impl Default for UltrawaveParams {
@@ -120,6 +293,7 @@ impl Default for UltrawaveParams {
             .with_value_to_string(formatters::v2s_f32_rounded(2)),
 
             channel: IntParam::new("Channel", 0, IntRange::Linear { min: 0, max: 7 }),
+            clock: IntParam::new("Engine Clock", 0, IntRange::Linear { min: 0, max: 1 }),
 
             record: RamRecordParams::default(),
             play: RamPlayParams::default(),
@@ -129,9 +303,49 @@ impl Default for UltrawaveParams {
             fltq: IntParam::new("Filter Q", 0, IntRange::Linear { min: 0, max: 127 }),
             fltw: IntParam::new("Filter Width", 0, IntRange::Linear { min: 0, max: 127 }),
 
+            note_routing: IntParam::new("Note Routing", 0, IntRange::Linear { min: 0, max: 1 }),
+
+            record_note: IntParam::new("Record Note", 0, IntRange::Linear { min: 0, max: 127 }),
+            record_switch: IntParam::new("Record", 0, IntRange::Linear { min: 0, max: 1 }),
+
+            memory_size: IntParam::new("Sample Memory", 32, IntRange::Linear { min: 1, max: 256 })
+                .with_unit(" MB"),
+            md_memory: IntParam::new("MD Memory", 0, IntRange::Linear { min: 0, max: 1 }),
+            memory_full: IntParam::new("Memory Full", 0, IntRange::Linear { min: 0, max: 1 }),
+
+            mixer: Default::default(),
+        }
+    }
+}
+
+#[derive(Params)]
+pub struct MixerChannelParams {
+    #[id = "vol"]
+    pub vol: IntParam,
+    #[id = "pan"]
+    pub pan: IntParam,
+    #[id = "mute"]
+    pub mute: IntParam,
+    #[id = "solo"]
+    pub solo: IntParam,
+    /// Main mix send (0: off, 1: on)
+    #[id = "main"]
+    pub main: IntParam,
+    /// Aux output bus (0: none, 1-8: bus); channels sharing a bus form a group
+    #[id = "bus"]
+    pub bus: IntParam,
+}
+
+impl Default for MixerChannelParams {
+    fn default() -> Self {
+        Self {
             // Output (0-127)
             vol: IntParam::new("Volume", 100, IntRange::Linear { min: 0, max: 127 }),
             pan: IntParam::new("Pan", 64, IntRange::Linear { min: 0, max: 127 }),
+            mute: IntParam::new("Mute", 0, IntRange::Linear { min: 0, max: 1 }),
+            solo: IntParam::new("Solo", 0, IntRange::Linear { min: 0, max: 1 }),
+            main: IntParam::new("Main Mix", 1, IntRange::Linear { min: 0, max: 1 }),
+            bus: IntParam::new("Aux Bus", 0, IntRange::Linear { min: 0, max: 8 }),
         }
     }
 }
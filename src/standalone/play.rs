@@ -2,41 +2,53 @@ use nih_plug::prelude::*;
 use std::sync::Arc;
 
 use crate::dsp::filter::ResonantFilter;
-use crate::machines::ram_play::{RamPlay, RamPlayParams as RamPlayMachineParams};
+use crate::dsp::sample_buffer::SampleBuffer;
+use crate::machines::ram_play::{slice_for_note, RamPlay, RamPlayParams as RamPlayMachineParams};
+use crate::machines::shared::{get_standalone_registry, BufferRegistry, SharedBuffer};
 use crate::params::RamPlayParams;
 use crate::standalone::play_editor;
 
+/// Work handed off the audio thread
+pub enum PlayTask {
+    /// A snapshot the player moved off, freed here so the audio thread
+    /// never deallocates
+    FreeSnapshot(Arc<SharedBuffer>),
+    /// A buffer the voices moved off
+    FreeBuffer(Arc<SampleBuffer>),
+}
+
 pub struct StandalonePlay {
     params: Arc<RamPlayParams>,
     editor_state: Arc<nih_plug_vizia::ViziaState>,
     sample_rate: f32,
     ram_play: RamPlay,
     filter: ResonantFilter,
-    test_buffer_loaded: bool,
+    registry: Arc<BufferRegistry>,
+    /// Slot and generation currently played
+    followed: Option<(usize, u64)>,
+    /// Snapshot currently played, held so the registry moving on never
+    /// leaves the audio thread with the last reference
+    snapshot: Option<Arc<SharedBuffer>>,
 }
 
 impl Default for StandalonePlay {
     fn default() -> Self {
-        let sample_rate = 44100.0;
-        let mut ram_play = RamPlay::new(sample_rate);
-
-        // Load a test buffer (saw wave)
-        let test_buffer: Vec<i16> = (0..10000)
-            .map(|i| {
-                let phase = (i % 100) as f32 / 100.0;
-                (phase * 2.0 - 1.0) * 2047.0 as f32
-            })
-            .map(|f| f as i16)
-            .collect();
-        ram_play.load_buffer(test_buffer, 0);
+        Self::with_registry(get_standalone_registry())
+    }
+}
 
+impl StandalonePlay {
+    fn with_registry(registry: Arc<BufferRegistry>) -> Self {
+        let sample_rate = 44100.0;
         Self {
             params: Arc::new(RamPlayParams::default()),
             editor_state: play_editor::default_state(),
             sample_rate,
-            ram_play,
+            ram_play: RamPlay::new(sample_rate),
             filter: ResonantFilter::new(sample_rate),
-            test_buffer_loaded: true,
+            registry,
+            followed: None,
+            snapshot: None,
         }
     }
 }
@@ -55,12 +67,12 @@ impl Plugin for StandalonePlay {
         names: PortNames::const_default(),
     }];
 
-    const MIDI_INPUT: MidiConfig = MidiConfig::Basic;
+    const MIDI_INPUT: MidiConfig = MidiConfig::MidiCCs;
     const MIDI_OUTPUT: MidiConfig = MidiConfig::None;
     const SAMPLE_ACCURATE_AUTOMATION: bool = true;
 
     type SysExMessage = ();
-    type BackgroundTask = ();
+    type BackgroundTask = PlayTask;
 
     fn params(&self) -> Arc<dyn Params> {
         self.params.clone()
@@ -88,31 +100,8 @@ impl Plugin for StandalonePlay {
         _aux: &mut AuxiliaryBuffers,
         context: &mut impl ProcessContext<Self>,
     ) -> ProcessStatus {
-        while let Some(event) = context.next_event() {
-            match event {
-                NoteEvent::NoteOn { velocity, .. } => {
-                    let chan = 0;
-                    if self.ram_play.buffer_len(chan) > 0 || self.test_buffer_loaded {
-                        let play_params = RamPlayMachineParams {
-                            strt: self.params.strt.value(),
-                            end: self.params.end.value(),
-                            pitch: self.params.pitch.value(),
-                            hold: self.params.hold.value(),
-                            dec: self.params.dec.value(),
-                            rtrg: self.params.rtrg.value(),
-                            rtim: self.params.rtim.value(),
-                            srr: self.params.srr.value(),
-                            vol: ((velocity * 127.0) as i32).min(127),
-                        };
-                        self.ram_play.trigger(&play_params, chan);
-                    }
-                }
-                NoteEvent::NoteOff { .. } => {
-                    let chan = 0;
-                    self.ram_play.stop(chan);
-                }
-                _ => {}
-            }
+        if let Some(previous) = self.follow_slot() {
+            context.execute_background(PlayTask::FreeSnapshot(previous));
         }
 
         // Update filter parameters (reusing srr and rtim for filter)
@@ -125,10 +114,20 @@ impl Plugin for StandalonePlay {
             crate::dsp::filter::FilterMode::LowPass,
         );
 
-        for channel_samples in buffer.iter_samples() {
+        let mut next_event = context.next_event();
+        for (sample_idx, channel_samples) in buffer.iter_samples().enumerate() {
+            // Apply events due at this sample before rendering it
+            while let Some(event) = next_event {
+                if event.timing() > sample_idx as u32 {
+                    break;
+                }
+                self.handle_event(event);
+                next_event = context.next_event();
+            }
+
             let chan = 0;
-            let sample_out = self.ram_play.process(chan);
-            let (left, right) = self.filter.process_stereo(sample_out, sample_out);
+            let (play_l, play_r) = self.ram_play.process_stereo(chan);
+            let (left, right) = self.filter.process_stereo(play_l, play_r);
 
             let mut out_idx = 0;
             for sample in channel_samples {
@@ -136,11 +135,97 @@ impl Plugin for StandalonePlay {
                 out_idx += 1;
             }
         }
+
+        self.ram_play
+            .release_retired(|buffer| context.execute_background(PlayTask::FreeBuffer(buffer)));
         ProcessStatus::Normal
     }
 
     fn task_executor(&mut self) -> TaskExecutor<Self> {
-        Box::new(|_task| {})
+        Box::new(|task| match task {
+            PlayTask::FreeSnapshot(snapshot) => drop(snapshot),
+            PlayTask::FreeBuffer(buffer) => drop(buffer),
+        })
+    }
+}
+
+impl StandalonePlay {
+    /// Picks up the slot's buffer when the slot param changes or a Record
+    /// instance publishes to it. Recording into the buffer needs no update.
+    /// Returns the snapshot played before, for freeing off the audio thread.
+    fn follow_slot(&mut self) -> Option<Arc<SharedBuffer>> {
+        let slot = self.params.slot.value() as usize;
+        let generation = self.registry.generation(slot);
+        if self.followed == Some((slot, generation)) {
+            return None;
+        }
+        // Keep the generation read before loading: it may lag the snapshot,
+        // which only costs a reload, and it counts changes from other
+        // processes that the snapshot doesn't
+        let snapshot = self.registry.snapshot(slot)?;
+        self.ram_play.share_buffer(snapshot.buffer.clone(), 0);
+        self.followed = Some((slot, generation));
+        self.snapshot.replace(snapshot)
+    }
+
+    fn handle_event(&mut self, event: PluginNoteEvent<Self>) {
+        match event {
+            NoteEvent::NoteOn { note, velocity, .. } => {
+                let chan = 0;
+                let slices = self.params.slices.value();
+                let Some(slice) = slice_for_note(note, self.params.slice_base.value(), slices)
+                else {
+                    return;
+                };
+                // Notes select slices while slicing, so only key-track whole buffers
+                let key = if slices <= 1 && self.params.ktrk.value() == 1 {
+                    note as i32 - self.params.root.value()
+                } else {
+                    0
+                };
+                if self.ram_play.buffer_len(chan) > 0 {
+                    let play_params = RamPlayMachineParams {
+                        strt: self.params.strt.value(),
+                        end: self.params.end.value(),
+                        pitch: self.params.pitch.value(),
+                        hold: self.params.hold.value(),
+                        dec: self.params.dec.value(),
+                        rtrg: self.params.rtrg.value(),
+                        rtim: self.params.rtim.value(),
+                        srr: self.params.srr.value(),
+                        vol: ((velocity * 127.0) as i32).min(127),
+                        intp: self.params.intp.value(),
+                        loop_mode: self.params.loop_mode.value(),
+                        lstr: self.params.lstr.value(),
+                        plbk: self.params.plbk.value(),
+                        slices,
+                        slice,
+                        dith: self.params.dith.value(),
+                        key,
+                        tune: self.params.tune.value(),
+                        bend: self.params.bend.value(),
+                        dclk: self.params.dclk.value(),
+                        atk: self.params.atk.value(),
+                        rel: self.params.rel.value(),
+                        dcrv: self.params.dcrv.value(),
+                        envm: self.params.envm.value(),
+                        poly: self.params.poly.value(),
+                        voices: self.params.voices.value(),
+                        steal: self.params.steal.value(),
+                    };
+                    self.ram_play.trigger_note(&play_params, chan, note);
+                }
+            }
+            NoteEvent::MidiPitchBend { value, .. } => {
+                let chan = 0;
+                self.ram_play.set_pitch_bend(value * 2.0 - 1.0, chan);
+            }
+            NoteEvent::NoteOff { note, .. } => {
+                let chan = 0;
+                self.ram_play.release_note(chan, note);
+            }
+            _ => {}
+        }
     }
 }
 
@@ -160,3 +245,37 @@ impl Vst3Plugin for StandalonePlay {
 }
 
 // Standalone only - no plugin exports
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_following_a_publish_does_not_free() {
+        let registry = Arc::new(BufferRegistry::new());
+        registry.replace_buffer(0, vec![1, 2, 3]);
+        let mut play = StandalonePlay::with_registry(registry.clone());
+        // Also registers the thread with arc-swap
+        assert!(play.follow_slot().is_none());
+        play.ram_play.release_retired(drop);
+
+        let old = Arc::downgrade(&registry.snapshot(0).unwrap());
+        registry.replace_buffer(0, vec![4, 5]);
+
+        let mut previous = None;
+        // Every voice and declick tail hands back its own reference
+        let mut retired = Vec::with_capacity(256);
+        let heap_calls = crate::alloc_check::heap_calls_in(|| {
+            previous = play.follow_slot();
+            play.ram_play.release_retired(|buffer| retired.push(buffer));
+        });
+        assert_eq!(heap_calls, 0);
+        assert_eq!(play.ram_play.buffer_len(0), 2);
+
+        // The old snapshot and its samples are only freed by the caller
+        assert!(old.upgrade().is_some());
+        drop(previous);
+        drop(retired);
+        assert!(old.upgrade().is_none());
+    }
+}
@@ -2,16 +2,33 @@ use nih_plug::prelude::*;
 use std::sync::Arc;
 
 use crate::dsp::filter::ResonantFilter;
+use crate::dsp::sample_buffer::SampleBuffer;
 use crate::machines::ram_record::{RamRecord, RamRecordParams as RamRecordMachineParams};
+use crate::machines::shared::{get_standalone_registry, BufferRegistry};
 use crate::params::RamRecordParams;
 use crate::standalone::record_editor;
 
+/// Work handed off the audio thread
+pub enum RecordTask {
+    /// Point a registry slot at the recording
+    Publish {
+        slot: usize,
+        buffer: Arc<SampleBuffer>,
+    },
+}
+
 pub struct StandaloneRecord {
     params: Arc<RamRecordParams>,
     editor_state: Arc<nih_plug_vizia::ViziaState>,
     sample_rate: f32,
     ram_record: RamRecord,
     filter: ResonantFilter,
+    /// Host song position in beats at the start of the block
+    block_position: Option<f64>,
+    beats_per_sample: f64,
+    registry: Arc<BufferRegistry>,
+    /// Slot the recording is currently published to
+    published_slot: Option<usize>,
 }
 
 impl Default for StandaloneRecord {
@@ -23,6 +40,10 @@ impl Default for StandaloneRecord {
             sample_rate,
             ram_record: RamRecord::new(sample_rate),
             filter: ResonantFilter::new(sample_rate),
+            block_position: None,
+            beats_per_sample: 0.0,
+            registry: get_standalone_registry(),
+            published_slot: None,
         }
     }
 }
@@ -46,7 +67,7 @@ impl Plugin for StandaloneRecord {
     const SAMPLE_ACCURATE_AUTOMATION: bool = true;
 
     type SysExMessage = ();
-    type BackgroundTask = ();
+    type BackgroundTask = RecordTask;
 
     fn params(&self) -> Arc<dyn Params> {
         self.params.clone()
@@ -63,7 +84,13 @@ impl Plugin for StandaloneRecord {
         _context: &mut impl InitContext<Self>,
     ) -> bool {
         self.sample_rate = buffer_config.sample_rate;
+        self.ram_record.allocate(buffer_config.sample_rate);
         self.ram_record.set_sample_rate(buffer_config.sample_rate);
+        // The buffer may have been reallocated. Attach a shared memory slot
+        // here rather than on the audio thread, which would free the
+        // buffer it replaces.
+        self.attach_slot(self.params.slot.value() as usize);
+        self.published_slot = None;
         self.filter.set_sample_rate(buffer_config.sample_rate);
         true
     }
@@ -74,26 +101,28 @@ impl Plugin for StandaloneRecord {
         _aux: &mut AuxiliaryBuffers,
         context: &mut impl ProcessContext<Self>,
     ) -> ProcessStatus {
-        while let Some(event) = context.next_event() {
-            match event {
-                NoteEvent::NoteOn { .. } => {
-                    let chan = 0;
-                    let machine_params = RamRecordMachineParams {
-                        mlev: self.params.mlev.value(),
-                        mbal: self.params.mbal.value(),
-                        ilev: self.params.ilev.value(),
-                        ibal: self.params.ibal.value(),
-                        rec_len: self.params.rec_len.value(),
-                        rec_rate: self.params.rec_rate.value(),
-                    };
-                    self.ram_record.start_recording(&machine_params, true, chan);
-                }
-                NoteEvent::NoteOff { .. } => {
-                    let chan = 0;
-                    self.ram_record.stop_recording(chan);
-                }
-                _ => {}
+        let transport = context.transport();
+        let tempo = transport.tempo.unwrap_or(120.0);
+        let beats_per_bar = match (transport.time_sig_numerator, transport.time_sig_denominator) {
+            (Some(numerator), Some(denominator)) if denominator > 0 => {
+                numerator as f64 * 4.0 / denominator as f64
             }
+            _ => 4.0,
+        };
+        self.ram_record.set_tempo(tempo, beats_per_bar);
+        self.block_position = transport.pos_beats();
+        self.beats_per_sample = tempo / 60.0 / self.sample_rate as f64;
+
+        // Play instances follow the recording live through the slot.
+        // Publishing allocates a snapshot, so it happens in the background.
+        let slot = self.params.slot.value() as usize;
+        if self.published_slot != Some(slot) {
+            self.attach_slot(slot);
+            context.execute_background(RecordTask::Publish {
+                slot,
+                buffer: self.ram_record.shared_buffer(0),
+            });
+            self.published_slot = Some(slot);
         }
 
         // Update filter parameters
@@ -106,7 +135,17 @@ impl Plugin for StandaloneRecord {
             crate::dsp::filter::FilterMode::LowPass,
         );
 
-        for mut channel_samples in buffer.iter_samples() {
+        let mut next_event = context.next_event();
+        for (sample_idx, mut channel_samples) in buffer.iter_samples().enumerate() {
+            // Apply events due at this sample before recording it
+            while let Some(event) = next_event {
+                if event.timing() > sample_idx as u32 {
+                    break;
+                }
+                self.handle_event(event);
+                next_event = context.next_event();
+            }
+
             let chan = 0;
             let machine_params = RamRecordMachineParams {
                 mlev: self.params.mlev.value(),
@@ -115,12 +154,29 @@ impl Plugin for StandaloneRecord {
                 ibal: self.params.ibal.value(),
                 rec_len: self.params.rec_len.value(),
                 rec_rate: self.params.rec_rate.value(),
+                fmt: self.params.fmt.value(),
+                trig: self.params.trig.value(),
+                thr: self.params.thr.value(),
+                prer: self.params.prer.value(),
+                hldo: self.params.hldo.value(),
+                asto: self.params.asto.value(),
+                dub: self.params.dub.value(),
+                fdbk: self.params.fdbk.value(),
+                circ: self.params.circ.value(),
+                sync: self.params.sync.value(),
+                slen: self.params.slen.value(),
+                qnt: self.params.qnt.value(),
+                plen: self.params.plen.value(),
+                src: self.params.src.value(),
+                xslf: self.params.xslf.value(),
             };
 
-            // Collect samples first
-            let samples: Vec<f32> = channel_samples.iter_mut().map(|s| *s).collect();
-            let left = samples.get(0).copied().unwrap_or(0.0);
-            let right = samples.get(1).copied().unwrap_or(0.0);
+            // Read the input frame without allocating
+            let mut frame = [0.0; 2];
+            for (idx, sample) in channel_samples.iter_mut().take(2).enumerate() {
+                frame[idx] = *sample;
+            }
+            let [left, right] = frame;
 
             // Record the sample (main input is the audio input)
             self.ram_record
@@ -138,7 +194,68 @@ impl Plugin for StandaloneRecord {
     }
 
     fn task_executor(&mut self) -> TaskExecutor<Self> {
-        Box::new(|_task| {})
+        let registry = self.registry.clone();
+        Box::new(move |task| match task {
+            RecordTask::Publish { slot, buffer } => {
+                registry.publish(slot, buffer);
+            }
+        })
+    }
+}
+
+impl StandaloneRecord {
+    /// With shared memory, records straight into the slot so players in
+    /// other processes can follow it
+    fn attach_slot(&mut self, slot: usize) {
+        if let Some(buffer) = self.registry.shared_memory_buffer(slot) {
+            self.ram_record.set_buffer(0, buffer);
+        }
+    }
+
+    /// Song position in beats `offset` samples into the current block
+    fn song_position(&self, offset: u32) -> Option<f64> {
+        self.block_position
+            .map(|beats| beats + offset as f64 * self.beats_per_sample)
+    }
+
+    fn handle_event(&mut self, event: PluginNoteEvent<Self>) {
+        let timing = event.timing();
+        match event {
+            NoteEvent::NoteOn { .. } => {
+                let chan = 0;
+                let machine_params = RamRecordMachineParams {
+                    mlev: self.params.mlev.value(),
+                    mbal: self.params.mbal.value(),
+                    ilev: self.params.ilev.value(),
+                    ibal: self.params.ibal.value(),
+                    rec_len: self.params.rec_len.value(),
+                    rec_rate: self.params.rec_rate.value(),
+                    fmt: self.params.fmt.value(),
+                    trig: self.params.trig.value(),
+                    thr: self.params.thr.value(),
+                    prer: self.params.prer.value(),
+                    hldo: self.params.hldo.value(),
+                    asto: self.params.asto.value(),
+                    dub: self.params.dub.value(),
+                    fdbk: self.params.fdbk.value(),
+                    circ: self.params.circ.value(),
+                    sync: self.params.sync.value(),
+                    slen: self.params.slen.value(),
+                    qnt: self.params.qnt.value(),
+                    plen: self.params.plen.value(),
+                    src: self.params.src.value(),
+                    xslf: self.params.xslf.value(),
+                };
+                self.ram_record
+                    .set_song_position(self.song_position(timing));
+                self.ram_record.start_recording(&machine_params, true, chan);
+            }
+            NoteEvent::NoteOff { .. } => {
+                let chan = 0;
+                self.ram_record.stop_recording(chan);
+            }
+            _ => {}
+        }
     }
 }
 